@@ -0,0 +1,359 @@
+use crate::make::scan_sheet_layout::{PageLayout, LayoutResult};
+use crate::parse::BarsFound;
+use crate::parse::image::Image;
+use crate::parse::scan_error::ScanError;
+use crate::parse::scan_options::ScanOptions;
+
+use rayon::prelude::*;
+use rayon::{ThreadPoolBuilder, ThreadPoolBuildError};
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// files in a directory with any other extension are skipped, so that layouts and notes can live next to the images
+const IMAGE_EXTENSIONS: [&str; 9] = ["png", "jpg", "jpeg", "tif", "tiff", "pbm", "pgm", "ppm", "pnm"];
+
+/// The results of scanning a stack of sheets.
+pub struct BatchReport {
+    /// The sheets that were read, in the order they were given; some of their fields may still need review
+    pub results: Vec<LayoutResult>,
+    /// The sheets that couldn't be read at all, in the order they were given
+    pub rejected: Vec<RejectedSheet>,
+}
+
+/// A sheet that couldn't be read, and why.
+pub struct RejectedSheet {
+    /// The image file the sheet came from
+    pub path: PathBuf,
+    /// Which page of the file the sheet was on, counting from 1, if the file has more than one page
+    pub page: Option<usize>,
+    /// Why it couldn't be read
+    pub error: ScanError,
+}
+
+impl RejectedSheet {
+    /// Names the sheet the same way as the source of a `LayoutResult`
+    pub fn source(&self) -> String {
+        sheet_source(&self.path, self.page)
+    }
+}
+
+impl PageLayout {
+    /// Reads one image of a sheet made with this layout. The result's source is set to the image's path.
+    /// Only the first page of a multi-page TIFF is read.
+    pub fn read_sheet(&self, image_path: &Path, options: &ScanOptions) -> Result<LayoutResult, ScanError> {
+        let input_image = Image::read_from_file(image_path)?;
+        self.read_sheet_image(&input_image, sheet_source(image_path, None), options)
+    }
+
+    fn read_sheet_image(&self, image: &Image, source: String, options: &ScanOptions) -> Result<LayoutResult, ScanError> {
+        let bars_found = BarsFound::from_image(image, self, options)?;
+
+        let mut result = self.interpret_targets(&bars_found);
+        result.set_source(source);
+
+        Ok(result)
+    }
+
+    /// Reads every page of an image file as its own sheet, returning the page number alongside each outcome if there
+    /// is more than one page
+    fn read_sheet_pages(&self, image_path: &Path, options: &ScanOptions) -> Vec<(Option<usize>, Result<LayoutResult, ScanError>)> {
+        let pages = match Image::read_pages_from_file(image_path) {
+            Ok(pages) => pages,
+            Err(e) => return vec![(None, Err(e))],
+        };
+
+        // the pages are still decoded one at a time, but each is read on whichever core is free once it's decoded
+        let mut outcomes: Vec<_> = pages.enumerate()
+            .par_bridge()
+            .map(|(i, image)| (Some(i + 1), image))
+            .map(|(page, image)| (page, image.and_then(|image| self.read_sheet_image(&image, sheet_source(image_path, page), options))))
+            .collect();
+        outcomes.sort_by_key(|&(page, _)| page); // they finish in any order
+
+        if let [(ref mut page, ref mut outcome)] = outcomes[..] {
+            *page = None; // a file with one page is named by its path alone
+            if let Ok(ref mut result) = *outcome {
+                result.set_source(sheet_source(image_path, None));
+            }
+        }
+
+        outcomes
+    }
+
+    /// Reads every image in `image_paths` on all cores, pages of the same file included, carrying on past the ones that
+    /// can't be read.
+    /// Every page of a multi-page TIFF is read as a separate sheet, and named like `stack.tif#3`.
+    /// The report is in the same order as `image_paths` no matter which sheets finish first.
+    pub fn read_sheets(&self, image_paths: &[PathBuf], options: &ScanOptions) -> BatchReport {
+        let outcomes: Vec<_> = image_paths.par_iter()
+            .map(|path| self.read_sheet_pages(path, options))
+            .collect(); // collect keeps the order of image_paths
+
+        let mut results = Vec::with_capacity(image_paths.len());
+        let mut rejected = Vec::new();
+
+        for (path, pages) in image_paths.iter().zip(outcomes) {
+            for (page, outcome) in pages {
+                match outcome {
+                    Ok(result) => results.push(result),
+                    Err(error) => rejected.push(RejectedSheet { path: path.clone(), page, error }),
+                }
+            }
+        }
+
+        BatchReport { results, rejected }
+    }
+
+    /// Same as `read_sheets`, but using at most `thread_count` threads.
+    pub fn read_sheets_with_threads(&self, image_paths: &[PathBuf], options: &ScanOptions, thread_count: usize) -> Result<BatchReport, ThreadPoolBuildError> {
+        let pool = ThreadPoolBuilder::new().num_threads(thread_count).build()?;
+        Ok(pool.install(|| self.read_sheets(image_paths, options)))
+    }
+}
+
+impl BatchReport {
+    /// How many sheets were found, whether they could be read or not
+    pub fn sheet_count(&self) -> usize {
+        self.results.len() + self.rejected.len()
+    }
+
+    /// Writes a CSV of the rejected sheets, with a `source` and a `reason` column.
+    pub fn output_rejected_csv(&self, writer: impl Write) -> io::Result<()> {
+        let mut writer = csv::Writer::from_writer(writer);
+
+        writer.write_record(["source", "reason"])?;
+        for rejected in self.rejected.iter() {
+            writer.write_record([rejected.source(), rejected.error.to_string()])?;
+        }
+
+        writer.flush()
+    }
+}
+
+/// Expands `pattern` into the image files it names, sorted by path. A directory gives every image directly inside it,
+/// anything containing `*`, `?` or `[` is treated as a glob, and anything else is taken as a single file. A directory or
+/// glob with no images in it is an error, so that a mistyped pattern isn't taken for an empty stack of sheets.
+pub fn find_images(pattern: &str) -> Result<Vec<PathBuf>, FindImagesError> {
+    let path = Path::new(pattern);
+
+    let mut paths = if path.is_dir() {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let entry_path = entry?.path();
+            if entry_path.is_file() && has_image_extension(&entry_path) {
+                paths.push(entry_path);
+            }
+        }
+        paths
+    } else if pattern.contains(['*', '?', '[']) {
+        let mut paths = Vec::new();
+        for entry in glob::glob(pattern)? {
+            let entry_path = entry.map_err(io::Error::from)?;
+            if entry_path.is_file() {
+                paths.push(entry_path);
+            }
+        }
+        paths
+    } else {
+        vec![path.to_path_buf()] // a missing file is rejected when it is read, like any other unreadable sheet
+    };
+
+    if paths.is_empty() {
+        return Err(FindImagesError::NoImages);
+    }
+
+    paths.sort();
+    Ok(paths)
+}
+
+/// Names a sheet by the file it came from, and its page if the file has more than one
+fn sheet_source(path: &Path, page: Option<usize>) -> String {
+    match page {
+        Some(page) => format!("{}#{}", path.display(), page),
+        None => path.display().to_string(),
+    }
+}
+
+fn has_image_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| IMAGE_EXTENSIONS.iter().any(|known| known.eq_ignore_ascii_case(e)))
+        .unwrap_or(false)
+}
+
+/// Why a directory or glob couldn't be expanded into image files.
+#[derive(Debug)]
+pub enum FindImagesError {
+    /// A directory couldn't be listed
+    Io(io::Error),
+    /// The glob pattern isn't valid
+    Pattern(glob::PatternError),
+    /// The directory or glob didn't have any images in it
+    NoImages,
+}
+
+impl fmt::Display for FindImagesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FindImagesError::Io(ref e) => write!(f, "could not list images: {}", e),
+            FindImagesError::Pattern(ref e) => write!(f, "invalid pattern: {}", e),
+            FindImagesError::NoImages => write!(f, "no images found"),
+        }
+    }
+}
+
+impl std::error::Error for FindImagesError {}
+
+impl From<io::Error> for FindImagesError {
+    fn from(error: io::Error) -> FindImagesError {
+        FindImagesError::Io(error)
+    }
+}
+
+impl From<glob::PatternError> for FindImagesError {
+    fn from(error: glob::PatternError) -> FindImagesError {
+        FindImagesError::Pattern(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::make::scan_sheet_layout::{HighLevelPageDescription, HighLevelField, HighLevelKind, LayoutResultOption};
+    use crate::parse::test_sheets::{photograph, centered, PAPER, PENCIL};
+
+    use tiff::encoder::{TiffEncoder, colortype};
+
+    const SIZE: usize = 800;
+
+    fn layout() -> PageLayout {
+        HighLevelPageDescription {
+            document_title: "test".to_string(),
+            fields: vec![HighLevelField { kind: HighLevelKind::Boolean, descriptor: "yes".to_string() }],
+            edge_fiducials: false,
+            timing_tracks: false,
+        }.layout()
+    }
+
+    /// A photo of a sheet with its one bar filled in or not
+    fn sheet(filled: bool) -> Image {
+        let marks: &[(usize, _)] = if filled { &[(0, PENCIL)] } else { &[] };
+        photograph(&layout(), marks, SIZE, SIZE, &centered(SIZE, SIZE, 0.9))
+    }
+
+    /// A photo with no sheet in it to be found
+    fn blank() -> Image {
+        Image::from_fn(SIZE, SIZE, |_, _| PAPER)
+    }
+
+    fn write_tiff(path: &Path, pages: &[Image]) {
+        let mut encoder = TiffEncoder::new(fs::File::create(path).unwrap()).unwrap();
+        for page in pages {
+            let samples: Vec<u8> = (0..page.height)
+                .flat_map(|y| (0..page.base).flat_map(move |x| page.get_color(x, y).rgb()))
+                .collect();
+            encoder.write_image::<colortype::RGB8>(page.base as u32, page.height as u32, &samples).unwrap();
+        }
+    }
+
+    /// A directory of its own for a test's images
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("picture_scout_batch_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn answer(result: &LayoutResult) -> bool {
+        match result.fields().next() {
+            Some((_, &LayoutResultOption::Boolean(answer))) => answer,
+            other => panic!("{:?} read {:?}", result.source(), other),
+        }
+    }
+
+    #[test]
+    fn sheets_are_reported_in_the_order_given() {
+        let directory = directory("order");
+        let (stack, single) = (directory.join("stack.tif"), directory.join("single.png"));
+        write_tiff(&stack, &[sheet(true), sheet(false), blank(), sheet(true), sheet(false)]);
+        sheet(false).output_to_file(&single).unwrap();
+
+        let report = layout().read_sheets(&[stack.clone(), single.clone()], &ScanOptions::default());
+        fs::remove_dir_all(&directory).unwrap();
+
+        let sources: Vec<_> = report.results.iter().map(|result| result.source().unwrap().to_string()).collect();
+        let page = |page: usize| sheet_source(&stack, Some(page));
+        assert_eq!(sources, [page(1), page(2), page(4), page(5), single.display().to_string()]);
+        let answers: Vec<bool> = report.results.iter().map(answer).collect();
+        assert_eq!(answers, [true, false, true, false, false]);
+    }
+
+    #[test]
+    fn sheets_that_cant_be_read_are_reported_with_why() {
+        let directory = directory("rejected");
+        let (unreadable, missing, readable) = (directory.join("notes.png"), directory.join("missing.png"), directory.join("sheet.png"));
+        fs::write(&unreadable, "not an image").unwrap();
+        blank().output_to_file(&readable).unwrap(); // an image, but not of a sheet
+
+        let report = layout().read_sheets(&[unreadable.clone(), missing.clone(), readable.clone()], &ScanOptions::default());
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert!(report.results.is_empty());
+        assert_eq!(report.sheet_count(), 3);
+        let sources: Vec<String> = report.rejected.iter().map(RejectedSheet::source).collect();
+        assert_eq!(sources, [&unreadable, &missing, &readable].map(|path| path.display().to_string()));
+        assert!(report.rejected.iter().all(|rejected| rejected.page.is_none()));
+
+        let mut csv = Vec::new();
+        report.output_rejected_csv(&mut csv).unwrap();
+        let mut reader = csv::Reader::from_reader(csv.as_slice());
+        assert_eq!(reader.headers().unwrap(), vec!["source", "reason"]);
+        let rows: Vec<Vec<String>> = reader.deserialize().map(Result::unwrap).collect();
+        let expected: Vec<Vec<String>> = report.rejected.iter()
+            .map(|rejected| vec![rejected.source(), rejected.error.to_string()])
+            .collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn directories_give_the_images_directly_inside_them() {
+        let directory = directory("find_directory");
+        fs::create_dir_all(directory.join("nested")).unwrap();
+        for name in ["b.PNG", "a.tif", "notes.txt", "nested/c.png"] {
+            fs::write(directory.join(name), "").unwrap();
+        }
+        fs::create_dir_all(directory.join("empty")).unwrap();
+
+        let found = find_images(directory.to_str().unwrap());
+        let none = find_images(directory.join("empty").to_str().unwrap());
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(found.unwrap(), [directory.join("a.tif"), directory.join("b.PNG")]);
+        assert!(matches!(none, Err(FindImagesError::NoImages)));
+    }
+
+    #[test]
+    fn globs_give_the_files_they_match() {
+        let directory = directory("find_glob");
+        for name in ["sheet2.jpg", "sheet1.jpg", "other.jpg"] {
+            fs::write(directory.join(name), "").unwrap();
+        }
+
+        let found = find_images(directory.join("sheet?.jpg").to_str().unwrap());
+        let none = find_images(directory.join("*.png").to_str().unwrap());
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(found.unwrap(), [directory.join("sheet1.jpg"), directory.join("sheet2.jpg")]);
+        assert!(matches!(none, Err(FindImagesError::NoImages)));
+    }
+
+    #[test]
+    fn other_paths_are_taken_as_they_are() {
+        // whether it's there or not is found out when it's read
+        let path = std::env::temp_dir().join("picture_scout_no_such_sheet.png");
+
+        assert_eq!(find_images(path.to_str().unwrap()).unwrap(), [path]);
+    }
+}
@@ -0,0 +1,56 @@
+//! Make fill-in-the-bar scan sheets, and read them back from photos or scans.
+//!
+//! A sheet starts as a [`HighLevelPageDescription`], usually read from a JSON file. Laying it out gives a [`PageLayout`],
+//! which knows where every bar is printed. The layout can be rendered to SVG, and should be saved so that the sheets
+//! printed from it can still be read if the layout code changes.
+//!
+//! To read a filled in sheet, load its PNG, JPEG, TIFF, or Netpbm file as an [`Image`], find its filled in bars with
+//! [`BarsFound::from_image`], then ask the layout what they mean with [`PageLayout::interpret_targets`].
+//! [`PageLayout::read_sheets`] does all of this for a whole stack of sheets, carrying on past the ones that can't be read.
+//!
+//! ```no_run
+//! use picture_scout::{HighLevelPageDescription, PageLayout, Image, BarsFound, ScanOptions, Threshold};
+//! use std::path::Path;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let description = HighLevelPageDescription::read_from_file(Path::new("quiz.json"))?;
+//! let layout = description.layout();
+//! layout.output_svg_to_file(Path::new("quiz.svg"))?;
+//! layout.output_to_file(Path::new("quiz.layout.json"))?;
+//!
+//! // later, once the sheet has been printed and filled in
+//! let layout = PageLayout::read_from_file(Path::new("quiz.layout.json"))?;
+//! let image = Image::read_from_file(Path::new("photo.png"))?;
+//! // a local threshold copes with shadows across the page
+//! let options = ScanOptions { threshold: Threshold::Sauvola { window: 0.1, k: 0.3 }, ..ScanOptions::default() };
+//! let bars_found = BarsFound::from_image(&image, &layout, &options)?;
+//! let result = layout.interpret_targets(&bars_found);
+//!
+//! for (descriptor, value) in result.fields() {
+//!     println!("{}: {}", descriptor, value);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+#![warn(missing_docs)]
+
+mod batch;
+mod make;
+mod parse;
+mod util;
+
+pub use crate::make::scan_sheet_layout::{
+    HighLevelPageDescription, HighLevelField, HighLevelKind,
+    PageLayout, PageGeometry, TimingTracks,
+    LayoutResult, LayoutResultOption, BarState,
+};
+pub use crate::make::description_file::DescriptionError;
+pub use crate::make::layout_file::{LayoutFileError, layout_path_for_svg};
+pub use crate::parse::{BarsFound, DebugImages};
+pub use crate::parse::alignment::Alignment;
+pub use crate::parse::image::{Image, ImagePages, Color};
+pub use crate::parse::homography::Homography;
+pub use crate::parse::scan_error::ScanError;
+pub use crate::parse::scan_options::{ScanOptions, Threshold, TemplateDropout, InkClass, InkRole, ReadMode, FillThresholds, Interpolation};
+pub use crate::batch::{BatchReport, RejectedSheet, FindImagesError, find_images};
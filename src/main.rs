@@ -1,22 +1,429 @@
-use crate::make::dummy;
-use crate::parse::image::Image;
+use picture_scout::{HighLevelPageDescription, PageLayout, LayoutResult, Image, BarsFound, DebugImages, ScanError, BatchReport};
+use picture_scout::{ScanOptions, Threshold, TemplateDropout, InkClass, InkRole, ReadMode, FillThresholds, Interpolation};
+use picture_scout::{layout_path_for_svg, find_images};
 
-mod parse;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap::error::ErrorKind;
 
-mod make;
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
 
-mod util;
+const EXIT_INPUT_ERROR: i32 = 1; // a description, layout, or image couldn't be read, or an output couldn't be written
+const EXIT_UNREADABLE_SHEET: i32 = 3; // at least one sheet was loaded, but it or some of its fields couldn't be interpreted
+// clap exits with 2 on usage errors
+
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  success
+  1  an input could not be read or an output could not be written
+  2  invalid command line usage
+  3  at least one sheet, or a field on it, could not be read and needs review";
+
+#[derive(Parser)]
+#[command(name = "picture_scout", about = "Make and read fill-in-the-bar scan sheets", after_help = EXIT_CODES_HELP)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Render a page description to an SVG sheet, saving its layout next to it
+    Make {
+        /// The JSON page description
+        description: PathBuf,
+        /// Where to write the SVG; the layout is written to the same path with a `.layout.json` extension
+        #[arg(short, long, default_value = "sheet.svg")]
+        output: PathBuf,
+    },
+    /// Read filled in sheets and print the value of each field
+    Scan {
+        /// The layout saved when the sheet was made
+        layout: PathBuf,
+        /// Photos or scans of filled in sheets, directories of them, or glob patterns like 'stack/*.jpg'.
+        /// Every page of a multi-page TIFF is read as its own sheet
+        #[arg(required = true)]
+        images: Vec<String>,
+        /// How to print the results
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+        /// Also write the sheets that couldn't be read, with the reason, to this CSV file
+        #[arg(long)]
+        rejected: Option<PathBuf>,
+        /// How many sheets to read at once; defaults to one per CPU core
+        #[arg(short, long)]
+        jobs: Option<usize>,
+        #[command(flatten)]
+        options: ScanArgs,
+    },
+    /// Read one sheet, writing the intermediate images produced while parsing it
+    Debug {
+        /// The layout saved when the sheet was made
+        layout: PathBuf,
+        /// A photo or scan of a filled in sheet; only the first page of a multi-page TIFF is read
+        image: PathBuf,
+        /// The directory to write the intermediate images to
+        #[arg(short, long, default_value = ".")]
+        output_dir: PathBuf,
+        /// Write the intermediate images as PBM and PPM instead of PNG
+        #[arg(long)]
+        netpbm: bool,
+        #[command(flatten)]
+        options: ScanArgs,
+    },
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Reading options")]
+struct ScanArgs {
+    /// How to tell marks from the paper; use a local method for photos with shadows or uneven lighting
+    #[arg(long, value_enum, default_value_t = ThresholdMethod::Fixed)]
+    threshold: ThresholdMethod,
+    /// Pixels darker than this level, from 0 to 255, are marks with the fixed threshold
+    #[arg(long, default_value_t = 110)]
+    dark_level: u8,
+    /// The side of the window local thresholds look at, as a fraction of the image's shorter side
+    #[arg(long, default_value_t = 0.1)]
+    window: f64,
+    /// How many levels darker than the window's mean a mark has to be with the mean threshold
+    #[arg(long, default_value_t = 15.0)]
+    offset: f64,
+    /// The k parameter of the Sauvola threshold; higher keeps fewer faint marks
+    #[arg(long, default_value_t = 0.3)]
+    sauvola_k: f64,
+    /// Count pixels the color of the printed template as marks when they are dark, rather than dropping them
+    #[arg(long)]
+    keep_template: bool,
+    /// How to find the filled in bars once the aligners are found
+    #[arg(long, value_enum, default_value_t = ReadMethod::Warp)]
+    read_mode: ReadMethod,
+    /// Bars with at least this fraction of them dark are filled in
+    #[arg(long, default_value_t = 0.5, value_parser = fraction)]
+    set_threshold: f64,
+    /// Bars with at most this fraction of them dark are empty; bars in between are ambiguous, and left for review
+    #[arg(long, default_value_t = 0.2, value_parser = fraction)]
+    unset_threshold: f64,
+    /// How to sample the photo when straightening out the page with the warp read mode
+    #[arg(long, value_enum, default_value_t = InterpolationMethod::Nearest)]
+    interpolation: InterpolationMethod,
+    /// Tell pencil, blue pen, and red pen apart, and do this with red pen marks; by default every mark counts
+    #[arg(long, value_enum)]
+    red_pen: Option<RedPen>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ReadMethod {
+    /// Straighten out the page, then measure each bar of the layout in it
+    Warp,
+    /// Measure each bar of the layout where it lands in the photo; faster, at full resolution
+    Projection,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum InterpolationMethod {
+    /// The nearest pixel; fastest, but thin bars can alias
+    Nearest,
+    /// A blend of the four nearest pixels
+    Bilinear,
+    /// A sharper blend of the sixteen nearest pixels
+    Bicubic,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RedPen {
+    /// Read red pen like any other mark
+    Mark,
+    /// Leave red pen out
+    Ignore,
+    /// Report red pen marks separately, as overrides
+    Override,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ThresholdMethod {
+    /// One fixed level for every image
+    Fixed,
+    /// One level per image, chosen with Otsu's method
+    Otsu,
+    /// Darker than the mean of the surrounding window by an offset
+    Mean,
+    /// Sauvola's method, which keeps blank paper in shadow light
+    Sauvola,
+}
+
+/// Parses a number from 0 to 1
+fn fraction(arg: &str) -> Result<f64, String> {
+    let value: f64 = arg.parse().map_err(|e| format!("{}", e))?;
+    if (0.0..=1.0).contains(&value) {
+        Ok(value)
+    } else {
+        Err(format!("{} is not between 0 and 1", value))
+    }
+}
+
+impl ScanArgs {
+    fn options(&self) -> ScanOptions {
+        if self.unset_threshold > self.set_threshold {
+            // clap can't check one argument against another's value, so this is reported as a usage error by hand
+            Cli::command().error(ErrorKind::ValueValidation, format!("--unset-threshold ({}) can't be above --set-threshold ({})",
+                self.unset_threshold, self.set_threshold)).exit();
+        }
+
+        let threshold = match self.threshold {
+            ThresholdMethod::Fixed => Threshold::Fixed(self.dark_level),
+            ThresholdMethod::Otsu => Threshold::Otsu,
+            ThresholdMethod::Mean => Threshold::MeanOfWindow { window: self.window, offset: self.offset },
+            ThresholdMethod::Sauvola => Threshold::Sauvola { window: self.window, k: self.sauvola_k },
+        };
+
+        let template_dropout = if self.keep_template { None } else { Some(TemplateDropout::default()) };
+
+        let ink_classes = match self.red_pen {
+            Some(red_pen) => {
+                let role = match red_pen {
+                    RedPen::Mark => InkRole::Mark,
+                    RedPen::Ignore => InkRole::Ignore,
+                    RedPen::Override => InkRole::Override,
+                };
+                vec![InkClass::graphite(InkRole::Mark), InkClass::blue(InkRole::Mark), InkClass::red(role)]
+            },
+            None => Vec::new(),
+        };
+
+        let read_mode = match self.read_mode {
+            ReadMethod::Warp => ReadMode::Warp,
+            ReadMethod::Projection => ReadMode::Projection,
+        };
+
+        let fill_thresholds = FillThresholds { set: self.set_threshold, unset: self.unset_threshold };
+
+        let interpolation = match self.interpolation {
+            InterpolationMethod::Nearest => Interpolation::Nearest,
+            InterpolationMethod::Bilinear => Interpolation::Bilinear,
+            InterpolationMethod::Bicubic => Interpolation::Bicubic,
+        };
+
+        ScanOptions { threshold, template_dropout, ink_classes, read_mode, fill_thresholds, interpolation }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    /// One line per field
+    Text,
+    /// An object for a single sheet, or an array of them if there are several sheets
+    Json,
+    /// A header row, then one row per sheet
+    Csv,
+}
 
 fn main() {
-    let description = dummy();
+    let cli = Cli::parse();
+
+    let exit_code = match cli.command {
+        Command::Make { description, output } => make(&description, &output),
+        Command::Scan { layout, images, format, rejected, jobs, options } => {
+            scan(&layout, &images, format, rejected.as_deref(), jobs, &options.options())
+        },
+        Command::Debug { layout, image, output_dir, netpbm, options } => debug(&layout, &image, &output_dir, netpbm, &options.options()),
+    };
+
+    process::exit(exit_code);
+}
+
+fn make(description_path: &Path, svg_path: &Path) -> i32 {
+    let description = match HighLevelPageDescription::read_from_file(description_path) {
+        Ok(description) => description,
+        Err(e) => return report(description_path, e, EXIT_INPUT_ERROR),
+    };
+
     let layout = description.layout();
 
-    svg::save("test.svg", &layout.to_svg()).unwrap();
+    if let Err(e) = layout.output_svg_to_file(svg_path) {
+        return report(svg_path, e, EXIT_INPUT_ERROR);
+    }
+
+    let layout_path = layout_path_for_svg(svg_path);
+    if let Err(e) = layout.output_to_file(&layout_path) {
+        return report(&layout_path, e, EXIT_INPUT_ERROR);
+    }
+
+    0
+}
+
+fn scan(layout_path: &Path, patterns: &[String], format: OutputFormat, rejected_path: Option<&Path>, jobs: Option<usize>, options: &ScanOptions) -> i32 {
+    let layout = match PageLayout::read_from_file(layout_path) {
+        Ok(layout) => layout,
+        Err(e) => return report(layout_path, e, EXIT_INPUT_ERROR),
+    };
+
+    let mut image_paths = Vec::new();
+    for pattern in patterns.iter() {
+        match find_images(pattern) {
+            Ok(paths) => image_paths.extend(paths),
+            Err(e) => return report(Path::new(pattern), e, EXIT_INPUT_ERROR),
+        }
+    }
+
+    let batch = match jobs {
+        Some(jobs) => match layout.read_sheets_with_threads(&image_paths, options, jobs) {
+            Ok(batch) => batch,
+            Err(e) => {
+                eprintln!("error: could not start {} threads: {}", jobs, e);
+                return EXIT_INPUT_ERROR;
+            },
+        },
+        None => layout.read_sheets(&image_paths, options),
+    };
+
+    let mut exit_code = 0;
+
+    if batch.results.iter().any(|result| result.needs_review().next().is_some()) {
+        exit_code = EXIT_UNREADABLE_SHEET;
+    }
+
+    for result in batch.results.iter().filter(|result| result.alignment().needs_review()) {
+        eprintln!("warning: {}: the aligners disagree by {:.4} of the page, so bars may have been read from the wrong place",
+            result.source().unwrap_or("sheet"), result.alignment().reprojection_error());
+        exit_code = EXIT_UNREADABLE_SHEET;
+    }
+
+    for result in batch.results.iter().filter(|result| result.alignment().estimated()) {
+        eprintln!("warning: {}: only three aligners were found, so the page was straightened out without correcting perspective",
+            result.source().unwrap_or("sheet"));
+    }
+
+    for rejected in batch.rejected.iter() {
+        let code = scan_exit_code(&rejected.error);
+        exit_code = exit_code.max(code);
+        report(Path::new(&rejected.source()), &rejected.error, code);
+    }
+
+    let single_sheet = patterns.len() == 1 && batch.sheet_count() == 1 && !Path::new(&patterns[0]).is_dir();
+    match print_results(&batch.results, format, single_sheet) {
+        Err(ref e) if is_broken_pipe(e) => {}, // whatever was reading the results, like `head`, has all it wanted
+        Err(e) => {
+            eprintln!("error: could not write results: {}", e);
+            return EXIT_INPUT_ERROR;
+        },
+        Ok(()) => {},
+    }
+
+    if let Some(rejected_path) = rejected_path {
+        if let Err(e) = write_rejected(&batch, rejected_path) {
+            return report(rejected_path, e, EXIT_INPUT_ERROR);
+        }
+    }
+
+    if batch.sheet_count() > 1 {
+        eprintln!("read {} of {} sheets", batch.results.len(), batch.sheet_count());
+    }
+
+    exit_code
+}
+
+fn write_rejected(batch: &BatchReport, path: &Path) -> io::Result<()> {
+    batch.output_rejected_csv(io::BufWriter::new(fs::File::create(path)?))
+}
+
+fn print_results(results: &[LayoutResult], format: OutputFormat, single_sheet: bool) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+
+    match format {
+        OutputFormat::Text => for result in results.iter() {
+            writeln!(stdout, "{}:", result.source().unwrap_or(""))?;
+            result.describe_results(&mut stdout)?;
+        },
+        OutputFormat::Json if single_sheet => if let Some(result) = results.first() {
+            result.output_json(&mut stdout)?;
+            writeln!(stdout)?;
+        },
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut stdout, results)?;
+            writeln!(stdout)?;
+        },
+        OutputFormat::Csv => LayoutResult::output_csv(results, &mut stdout)?,
+    }
+
+    stdout.flush()
+}
+
+/// Whether writing failed only because the other end of a pipe was closed
+fn is_broken_pipe(error: &io::Error) -> bool {
+    // the CSV writer wraps the error it got in its own
+    let csv_error = error.get_ref().and_then(|inner| inner.downcast_ref::<csv::Error>());
+
+    error.kind() == io::ErrorKind::BrokenPipe
+        || matches!(csv_error.map(csv::Error::kind), Some(csv::ErrorKind::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe)
+}
+
+fn debug(layout_path: &Path, image_path: &Path, output_dir: &Path, netpbm: bool, options: &ScanOptions) -> i32 {
+    let layout = match PageLayout::read_from_file(layout_path) {
+        Ok(layout) => layout,
+        Err(e) => return report(layout_path, e, EXIT_INPUT_ERROR),
+    };
+
+    let input_image = match Image::read_from_file(image_path) {
+        Ok(image) => image,
+        Err(e) => return report(image_path, &e, scan_exit_code(&e)),
+    };
+
+    let mut debug_images = DebugImages::new();
+    let bars_found = BarsFound::from_image_with_debug(&input_image, &layout, options, &mut debug_images);
+
+    if let Err(e) = fs::create_dir_all(output_dir) {
+        return report(output_dir, e, EXIT_INPUT_ERROR);
+    }
+
+    let written = if netpbm {
+        debug_images.output_netpbm_to_dir(output_dir)
+    } else {
+        debug_images.output_to_dir(output_dir)
+    };
+
+    match written {
+        Ok(paths) => for path in paths {
+            eprintln!("wrote {}", path.display());
+        },
+        Err(e) => return report(output_dir, e, EXIT_INPUT_ERROR),
+    }
+
+    let bars_found = match bars_found {
+        Ok(bars_found) => bars_found,
+        Err(e) => return report(image_path, &e, scan_exit_code(&e)),
+    };
+
+    eprintln!("found {} bars: {:?}", bars_found.bars().len(), bars_found.bars());
+    if !bars_found.overrides().is_empty() {
+        eprintln!("found {} override bars: {:?}", bars_found.overrides().len(), bars_found.overrides());
+    }
+
+    let result = layout.interpret_targets(&bars_found);
+    match result.describe_results(io::stdout().lock()) {
+        Err(ref e) if is_broken_pipe(e) => {},
+        Err(e) => {
+            eprintln!("error: could not write results: {}", e);
+            return EXIT_INPUT_ERROR;
+        },
+        Ok(()) => {},
+    }
 
-    let input_image = Image::read_from_file("sample-images/image14.png"); // just one of the images that I'm testing with
+    if result.needs_review().next().is_some() { EXIT_UNREADABLE_SHEET } else { 0 }
+}
 
-    let deelio = parse::BarsFound::from_image(&input_image);
-    let result = layout.interpret_targets(&deelio).expect("bruh");
+fn scan_exit_code(error: &ScanError) -> i32 {
+    match *error {
+        ScanError::Io(_) | ScanError::UnrecognizedFormat | ScanError::Png(_) | ScanError::Jpeg(_) | ScanError::Tiff(_)
+            | ScanError::Netpbm(_) | ScanError::UnsupportedPixelFormat(_) => EXIT_INPUT_ERROR,
+        _ => EXIT_UNREADABLE_SHEET,
+    }
+}
 
-    result.describe_results(&description);
-}
\ No newline at end of file
+/// Prints an error about `path` and returns the exit code to use for it
+fn report(path: &Path, error: impl Display, exit_code: i32) -> i32 {
+    eprintln!("error: {}: {}", path.display(), error);
+    exit_code
+}
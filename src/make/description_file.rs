@@ -0,0 +1,63 @@
+use crate::make::scan_sheet_layout::HighLevelPageDescription;
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+// page descriptions are written as json, for example:
+// {
+//     "document_title": "test1",
+//     "fields": [
+//         { "kind": "boolean", "descriptor": "boom" },
+//         { "kind": { "seven_segment_display": 2 }, "descriptor": "another one" }
+//     ],
+//     "edge_fiducials": true,
+//     "timing_tracks": true
+// }
+// where edge_fiducials and timing_tracks are optional
+
+impl HighLevelPageDescription {
+    /// Reads a JSON page description. Syntax errors, unknown field kinds, bad digit counts and missing descriptors
+    /// are all reported with their line and column.
+    pub fn read_from_file(name: &Path) -> Result<HighLevelPageDescription, DescriptionError> {
+        let file = BufReader::new(File::open(name)?);
+
+        Ok(serde_json::from_reader(file)?)
+    }
+}
+
+/// Why a page description couldn't be read.
+#[derive(Debug)]
+pub enum DescriptionError {
+    /// The file couldn't be read
+    Io(io::Error),
+    /// The file isn't a valid description; also covers unknown kinds, bad digit counts, and missing descriptors
+    Syntax(serde_json::Error),
+}
+
+impl fmt::Display for DescriptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DescriptionError::Io(ref e) => write!(f, "could not read page description: {}", e),
+            DescriptionError::Syntax(ref e) => write!(f, "invalid page description: {}", e), // serde_json includes the line and column
+        }
+    }
+}
+
+impl std::error::Error for DescriptionError {}
+
+impl From<io::Error> for DescriptionError {
+    fn from(error: io::Error) -> DescriptionError {
+        DescriptionError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for DescriptionError {
+    fn from(error: serde_json::Error) -> DescriptionError {
+        match error.classify() {
+            serde_json::error::Category::Io => DescriptionError::Io(error.into()),
+            _ => DescriptionError::Syntax(error),
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use crate::make::scan_sheet_layout::PageLayout;
+
+use serde::{Serialize, Deserialize};
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+// bump this whenever the saved representation of a PageLayout changes in a way old files can't be read
+const LAYOUT_FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct LayoutFileRef<'a> {
+    format_version: u32,
+    layout: &'a PageLayout,
+}
+
+#[derive(Deserialize)]
+struct LayoutFile {
+    layout: PageLayout,
+}
+
+#[derive(Deserialize)]
+struct LayoutFileVersion {
+    format_version: u32,
+}
+
+impl PageLayout {
+    /// Saves the layout as JSON, so sheets made from it can be read later even if the layout code changes.
+    pub fn output_to_file(&self, name: &Path) -> Result<(), LayoutFileError> {
+        let file = BufWriter::new(File::create(name)?);
+
+        let saved = LayoutFileRef { format_version: LAYOUT_FORMAT_VERSION, layout: self };
+        serde_json::to_writer_pretty(file, &saved)?;
+
+        Ok(())
+    }
+
+    /// Loads a layout saved by `output_to_file`.
+    pub fn read_from_file(name: &Path) -> Result<PageLayout, LayoutFileError> {
+        let saved = fs::read_to_string(name)?;
+
+        // check the version first, so that an old file gives a useful error instead of a confusing missing field
+        let LayoutFileVersion { format_version } = serde_json::from_str(&saved)?;
+        if format_version != LAYOUT_FORMAT_VERSION {
+            return Err(LayoutFileError::UnsupportedVersion(format_version));
+        }
+
+        let LayoutFile { layout } = serde_json::from_str(&saved)?;
+        if !layout.bar_ids_in_order() {
+            return Err(LayoutFileError::BarIdsOutOfOrder);
+        }
+
+        Ok(layout)
+    }
+}
+
+/// The layout of `test.svg` is saved as `test.layout.json`
+pub fn layout_path_for_svg(svg_path: &Path) -> PathBuf {
+    svg_path.with_extension("layout.json")
+}
+
+/// Why a layout couldn't be saved or loaded.
+#[derive(Debug)]
+pub enum LayoutFileError {
+    /// The file couldn't be read or written
+    Io(io::Error),
+    /// The file isn't a valid layout
+    Syntax(serde_json::Error),
+    /// The file was saved by an incompatible version of this crate
+    UnsupportedVersion(u32),
+    /// The bars aren't numbered from 0 in the order they're laid out, so their results can't be told apart
+    BarIdsOutOfOrder,
+}
+
+impl fmt::Display for LayoutFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LayoutFileError::Io(ref e) => write!(f, "could not access layout file: {}", e),
+            LayoutFileError::Syntax(ref e) => write!(f, "invalid layout file: {}", e),
+            LayoutFileError::UnsupportedVersion(v) =>
+                write!(f, "layout file has format version {}, but only version {} is supported", v, LAYOUT_FORMAT_VERSION),
+            LayoutFileError::BarIdsOutOfOrder => write!(f, "invalid layout file: bar ids aren't numbered in layout order"),
+        }
+    }
+}
+
+impl std::error::Error for LayoutFileError {}
+
+impl From<io::Error> for LayoutFileError {
+    fn from(error: io::Error) -> LayoutFileError {
+        LayoutFileError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for LayoutFileError {
+    fn from(error: serde_json::Error) -> LayoutFileError {
+        match error.classify() {
+            serde_json::error::Category::Io => LayoutFileError::Io(error.into()),
+            _ => LayoutFileError::Syntax(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::make::scan_sheet_layout::{HighLevelPageDescription, HighLevelField, HighLevelKind};
+
+    fn layout() -> PageLayout {
+        HighLevelPageDescription {
+            document_title: "test".to_string(),
+            fields: vec![
+                HighLevelField { kind: HighLevelKind::Boolean, descriptor: "yes".to_string() },
+                HighLevelField { kind: HighLevelKind::Boolean, descriptor: "no".to_string() },
+            ],
+            edge_fiducials: false,
+            timing_tracks: false,
+        }.layout()
+    }
+
+    /// Saves `layout`, lets `edit` change the JSON, and loads it back
+    fn round_trip(name: &str, edit: impl Fn(String) -> String) -> Result<PageLayout, LayoutFileError> {
+        let path = std::env::temp_dir().join(format!("picture_scout_{}_{}.layout.json", name, std::process::id()));
+        layout().output_to_file(&path).unwrap();
+        fs::write(&path, edit(fs::read_to_string(&path).unwrap())).unwrap();
+        let layout = PageLayout::read_from_file(&path);
+        fs::remove_file(&path).unwrap();
+        layout
+    }
+
+    #[test]
+    fn saved_layouts_load() {
+        let loaded = round_trip("saved", |json| json).unwrap();
+
+        assert_eq!(loaded.bar_rects().len(), 2);
+    }
+
+    #[test]
+    fn edited_bar_ids_are_rejected() {
+        let result = round_trip("edited", |json| json.replacen("\"inner\": 1", "\"inner\": 5", 1));
+
+        assert!(matches!(result, Err(LayoutFileError::BarIdsOutOfOrder)));
+    }
+
+    #[test]
+    fn other_format_versions_are_rejected() {
+        let result = round_trip("version", |json| json.replacen("\"format_version\": 1", "\"format_version\": 0", 1));
+
+        assert!(matches!(result, Err(LayoutFileError::UnsupportedVersion(0))));
+    }
+}
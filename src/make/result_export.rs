@@ -0,0 +1,284 @@
+use crate::make::scan_sheet_layout::{LayoutResult, LayoutResultOption};
+use crate::parse::alignment::Alignment;
+
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeMap;
+
+use std::io::{self, Write};
+
+// a page exported as json looks like:
+// {
+//     "source": "image14.png",
+//     "fields": {
+//         "boom": { "status": "value", "value": true, "scores": [0.93] },
+//         "another one": { "status": "invalid", "value": null, "digit": 1, "bars": "1101000", "scores": [0.88, ...] }
+//     },
+//     "overrides": {
+//         "boom": { "status": "value", "value": false }
+//     },
+//     "alignment": { "fiducials_used": 4, "outliers": 0, "reprojection_error": 0.0, "needs_review": false, "estimated": false,
+//                    "timing_marks_used": 0 }
+// }
+// where scores say how much of each of the field's bars is dark, and overrides, read from marks in an override ink,
+// is left out if there are none. The outliers and reprojection error are always 0 unless the layout has edge fiducials
+//
+// as csv, there is a header row, then one row per page:
+// source,boom,boom status,another one,another one status,reprojection error,alignment estimated
+// image14.png,true,value,,invalid,0.0012,false
+
+impl LayoutResultOption {
+    /// One of `value`, `empty`, `invalid`, `ambiguous`, `erased`, or `cancelled`
+    pub fn status(&self) -> &'static str {
+        match *self {
+            LayoutResultOption::Boolean(_) | LayoutResultOption::Number(_) => "value",
+            LayoutResultOption::Empty => "empty",
+            LayoutResultOption::Invalid { .. } => "invalid",
+            LayoutResultOption::Ambiguous => "ambiguous",
+            LayoutResultOption::Erased => "erased",
+            LayoutResultOption::Cancelled => "cancelled",
+        }
+    }
+
+    fn value_string(&self) -> String {
+        match *self {
+            LayoutResultOption::Boolean(b) => b.to_string(),
+            LayoutResultOption::Number(n) => n.to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum FieldValue {
+    Boolean(bool),
+    Number(u64),
+}
+
+#[derive(Serialize)]
+struct FieldRecord {
+    status: &'static str,
+    value: Option<FieldValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    digit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bars: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scores: Option<Vec<f64>>,
+}
+
+impl Serialize for LayoutResultOption {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.record().serialize(serializer)
+    }
+}
+
+impl LayoutResultOption {
+    fn record(&self) -> FieldRecord {
+        let mut record = FieldRecord { status: self.status(), value: None, digit: None, bars: None, scores: None };
+
+        match *self {
+            LayoutResultOption::Boolean(b) => record.value = Some(FieldValue::Boolean(b)),
+            LayoutResultOption::Number(n) => record.value = Some(FieldValue::Number(n)),
+            LayoutResultOption::Invalid { digit, bars } => {
+                record.digit = Some(digit);
+                record.bars = Some(format!("{:07b}", bars));
+            },
+            LayoutResultOption::Empty | LayoutResultOption::Ambiguous | LayoutResultOption::Erased | LayoutResultOption::Cancelled => {},
+        }
+
+        record
+    }
+}
+
+struct FieldsByDescriptor<'a>(Vec<(&'a str, FieldRecord)>);
+
+impl<'a> Serialize for FieldsByDescriptor<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (descriptor, record) in self.0.iter() {
+            map.serialize_entry(descriptor, record)?;
+        }
+        map.end()
+    }
+}
+
+impl Serialize for Alignment {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(6))?;
+        map.serialize_entry("fiducials_used", &self.fiducials_used())?;
+        map.serialize_entry("outliers", &self.outliers())?;
+        map.serialize_entry("reprojection_error", &self.reprojection_error())?;
+        map.serialize_entry("needs_review", &self.needs_review())?;
+        map.serialize_entry("estimated", &self.estimated())?;
+        map.serialize_entry("timing_marks_used", &self.timing_marks_used())?;
+        map.end()
+    }
+}
+
+impl Serialize for LayoutResult {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let has_overrides = self.overrides().next().is_some();
+
+        let mut map = serializer.serialize_map(Some(if has_overrides { 4 } else { 3 }))?;
+        map.serialize_entry("source", &self.source())?;
+        let fields = self.fields().zip(self.scores())
+            .map(|((descriptor, value), (_, scores))| {
+                // three places is plenty to compare against the thresholds
+                let scores = scores.iter().map(|score| (score * 1000.0).round() / 1000.0).collect();
+                (descriptor, FieldRecord { scores: Some(scores), ..value.record() })
+            })
+            .collect();
+        map.serialize_entry("fields", &FieldsByDescriptor(fields))?;
+        if has_overrides {
+            let overrides = self.overrides().map(|(descriptor, value)| (descriptor, value.record())).collect();
+            map.serialize_entry("overrides", &FieldsByDescriptor(overrides))?;
+        }
+        map.serialize_entry("alignment", self.alignment())?;
+        map.end()
+    }
+}
+
+impl LayoutResult {
+    /// Writes this page as a JSON object, with its fields keyed by descriptor.
+    pub fn output_json(&self, writer: impl Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+
+    /// Writes a CSV with a header row, then one row per page. Every field gets a value column named after its
+    /// descriptor, and a status column. The last two columns say how far off the page's fiducials were, and whether
+    /// some had to be estimated. The pages should all come from the same layout.
+    pub fn output_csv(results: &[LayoutResult], writer: impl Write) -> io::Result<()> {
+        let mut writer = csv::Writer::from_writer(writer);
+
+        if let Some(first) = results.first() {
+            let mut header = vec![String::from("source")];
+            for (descriptor, _) in first.fields() {
+                header.push(descriptor.to_string());
+                header.push(format!("{} status", descriptor));
+            }
+            header.push(String::from("reprojection error"));
+            header.push(String::from("alignment estimated"));
+            writer.write_record(&header)?;
+        }
+
+        for result in results.iter() {
+            let mut row = vec![result.source().unwrap_or("").to_string()];
+            for (_, value) in result.fields() {
+                row.push(value.value_string());
+                row.push(value.status().to_string());
+            }
+            row.push(format!("{:.4}", result.alignment().reprojection_error()));
+            row.push(result.alignment().estimated().to_string());
+            writer.write_record(&row)?;
+        }
+
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::make::scan_sheet_layout::{PageLayout, HighLevelPageDescription, HighLevelField, HighLevelKind, BarState};
+    use crate::parse::BarsFound;
+
+    use serde_json::{json, Value};
+
+    /// A yes or no question followed by a one digit number
+    fn layout() -> PageLayout {
+        HighLevelPageDescription {
+            document_title: "test".to_string(),
+            fields: vec![
+                HighLevelField { kind: HighLevelKind::Boolean, descriptor: "yes".to_string() },
+                HighLevelField { kind: HighLevelKind::SevenSegmentDisplay(1), descriptor: "number".to_string() },
+            ],
+            edge_fiducials: false,
+            timing_tracks: false,
+        }.layout()
+    }
+
+    fn states(set: &[bool]) -> Vec<BarState> {
+        set.iter().map(|&set| if set { BarState::Set } else { BarState::Unset }).collect()
+    }
+
+    fn read(source: &str, found: BarsFound) -> LayoutResult {
+        let mut result = layout().interpret_targets(&found);
+        result.set_source(source.to_string());
+        result
+    }
+
+    /// A yes, and just the top bar of the digit, which isn't a digit
+    fn yes_and_no_digit() -> BarsFound {
+        BarsFound::from_states(states(&[true, true, false, false, false, false, false, false]))
+    }
+
+    fn as_json(result: &LayoutResult) -> Value {
+        let mut json = Vec::new();
+        result.output_json(&mut json).unwrap();
+        serde_json::from_slice(&json).unwrap()
+    }
+
+    #[test]
+    fn every_outcome_has_its_own_status() {
+        let statuses: Vec<&str> = [
+            LayoutResultOption::Boolean(true),
+            LayoutResultOption::Number(7),
+            LayoutResultOption::Empty,
+            LayoutResultOption::Invalid { digit: 0, bars: 0b1000000 },
+            LayoutResultOption::Ambiguous,
+            LayoutResultOption::Erased,
+            LayoutResultOption::Cancelled,
+        ].iter().map(LayoutResultOption::status).collect();
+
+        assert_eq!(statuses, ["value", "value", "empty", "invalid", "ambiguous", "erased", "cancelled"]);
+    }
+
+    #[test]
+    fn json_has_a_record_for_each_field() {
+        let json = as_json(&read("image14.png", yes_and_no_digit()));
+
+        assert_eq!(json, json!({
+            "source": "image14.png",
+            "fields": {
+                "yes": { "status": "value", "value": true, "scores": [1.0] },
+                "number": {
+                    "status": "invalid", "value": null, "digit": 0, "bars": "1000000",
+                    "scores": [1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+                },
+            },
+            "alignment": {
+                "fiducials_used": 4, "outliers": 0, "reprojection_error": 0.0, "needs_review": false, "estimated": false,
+                "timing_marks_used": 0,
+            },
+        }));
+    }
+
+    #[test]
+    fn json_has_overrides_only_when_there_are_some() {
+        let without = as_json(&read("a.png", yes_and_no_digit()));
+        let with = as_json(&read("b.png", yes_and_no_digit().with_override_states(states(&[true]))));
+
+        assert!(without.get("overrides").is_none());
+        assert_eq!(with["overrides"], json!({ "yes": { "status": "value", "value": true } }));
+    }
+
+    #[test]
+    fn csv_has_a_value_and_a_status_column_for_each_field() {
+        let results = [
+            read("a.png", yes_and_no_digit()),
+            // a 7, which is the top and both right bars
+            read("b.png", BarsFound::from_states(states(&[false, true, true, true, false, false, false, false]))),
+        ];
+
+        let mut csv = Vec::new();
+        LayoutResult::output_csv(&results, &mut csv).unwrap();
+
+        assert_eq!(String::from_utf8(csv).unwrap(), "\
+source,yes,yes status,number,number status,reprojection error,alignment estimated
+a.png,true,value,,invalid,0.0000,false
+b.png,false,value,7,value,0.0000,false
+");
+    }
+}
@@ -4,16 +4,23 @@ use svg::node::element::Rectangle;
 use svg::node;
 use svg::node::element;
 
+use crate::parse::image::Color;
+
 // lets do this on an 8.5 by 8.5 square cause itll fit on my paper
-const DOCUMENT_HEIGHT: f64 = 8.5;
+pub const DOCUMENT_HEIGHT: f64 = 8.5;
 
 pub const BAR_WIDTH: f64 = 0.01; // fractions
 pub const BAR_LENGTH: f64 = 0.03;
 
-const ALIGNER_INNER_RADIUS: f64 = 0.05;
+pub const ALIGNER_INNER_RADIUS: f64 = 0.05;
 pub const ALIGNER_OUTER_RADIUS: f64 = 0.05*10./7.;
+pub(crate) const ALIGNER_DOT_RADIUS: f64 = 0.012; // big enough to stay a blob of its own, apart from the ring, in a blurry photo
+pub(crate) const ALIGNER_DOT_ORBIT: f64 = 0.025; // how far from the center of the aligner its dots are, if it has more than one
+
+pub const TIMING_MARK_SIZE: f64 = 0.015;
 
-const TEMPLATE_COLOR: &'static str = "#CFE2F3"; // blue light enough that the image parser will ignore it
+// blue light enough that the image parser will ignore it, and which it drops by hue when lighting makes it look dark
+pub const TEMPLATE_COLOR: Color = Color::from_rgb(0xCF, 0xE2, 0xF3);
 
 const TITLE_FONT_SIZE: f64 = 0.13;
 pub const FIELD_FONT_SIZE: f64 = 0.05;
@@ -57,20 +64,48 @@ pub struct Element {
 impl Element {
     fn add_to_document(&self, doc: Document) -> Document {
         match self.kind {
-            ElementKind::Aligner => {
+            ElementKind::Aligner { dots } => {
+                let (cx, cy) = (self.x+ALIGNER_OUTER_RADIUS, self.y+ALIGNER_OUTER_RADIUS);
+
                 let outer = Circle::new()
-                    .set("cx", percentize(self.x+ALIGNER_OUTER_RADIUS))
-                    .set("cy", percentize(self.y+ALIGNER_OUTER_RADIUS))
+                    .set("cx", percentize(cx))
+                    .set("cy", percentize(cy))
                     .set("r", percentize(ALIGNER_OUTER_RADIUS))
                     .set("fill", "black");
 
                 let inner = Circle::new()
-                    .set("cx", percentize(self.x+ALIGNER_OUTER_RADIUS))
-                    .set("cy", percentize(self.y+ALIGNER_OUTER_RADIUS))
+                    .set("cx", percentize(cx))
+                    .set("cy", percentize(cy))
                     .set("r", percentize(ALIGNER_INNER_RADIUS))
                     .set("fill", "white");
 
-                doc.add(outer).add(inner)
+                let mut doc = doc.add(outer).add(inner);
+
+                // a lone dot goes in the middle, and more are spread around it starting from the top
+                let orbit = if dots == 1 { 0.0 } else { ALIGNER_DOT_ORBIT };
+                for i in 0..dots {
+                    let angle = 2.0*std::f64::consts::PI * i as f64 / dots as f64;
+
+                    let dot = Circle::new()
+                        .set("cx", percentize(cx + orbit*angle.sin()))
+                        .set("cy", percentize(cy - orbit*angle.cos()))
+                        .set("r", percentize(ALIGNER_DOT_RADIUS))
+                        .set("fill", "black");
+
+                    doc = doc.add(dot);
+                }
+
+                doc
+            },
+            ElementKind::TimingMark => {
+                let rect = Rectangle::new()
+                    .set("x", percentize(self.x))
+                    .set("y", percentize(self.y))
+                    .set("width", percentize(TIMING_MARK_SIZE))
+                    .set("height", percentize(TIMING_MARK_SIZE))
+                    .set("fill", "black");
+
+                doc.add(rect)
             },
             ElementKind::HorizontalBar | ElementKind::VerticalBar => {
                 let (w, h) = if let ElementKind::VerticalBar = self.kind {
@@ -84,7 +119,7 @@ impl Element {
                     .set("y", percentize(self.y))
                     .set("width", percentize(w))
                     .set("height", percentize(h))
-                    .set("fill", TEMPLATE_COLOR);
+                    .set("fill", TEMPLATE_COLOR.to_string());
 
                 doc.add(rect)
             },
@@ -95,7 +130,7 @@ impl Element {
                     .add(node::Text::new(s.clone()))
                     .set("x", percentize(self.x))
                     .set("y", percentize(self.y+font_size)) // for some reason text position is relative to bottom left corner
-                    .set("fill", TEMPLATE_COLOR)
+                    .set("fill", TEMPLATE_COLOR.to_string())
                     .set("font-size", to_points(font_size))
                     .set("font-family", "monospace");
 
@@ -107,7 +142,8 @@ impl Element {
 
 #[derive(PartialEq)]
 pub enum ElementKind {
-    Aligner,
+    Aligner { dots: usize }, // the dots inside tell the corners apart
+    TimingMark,
     HorizontalBar,
     VerticalBar,
     FieldDescriptor(String),
@@ -116,10 +152,7 @@ pub enum ElementKind {
 
 impl ElementKind {
     fn is_title(&self) -> bool {
-        match *self {
-            ElementKind::Title(_) => true,
-            _ => false,
-        }
+        matches!(*self, ElementKind::Title(_))
     }
 }
 
@@ -1,8 +1,15 @@
-use crate::make::scan_sheet_elements::{ScanSheetElements, ElementKind, BAR_WIDTH, BAR_LENGTH, FIELD_FONT_SIZE, ALIGNER_OUTER_RADIUS};
+use crate::make::scan_sheet_elements::{ScanSheetElements, ElementKind, BAR_WIDTH, BAR_LENGTH, FIELD_FONT_SIZE, ALIGNER_OUTER_RADIUS, DOCUMENT_HEIGHT, TIMING_MARK_SIZE};
 use crate::make::scan_sheet_elements::Element;
 use svg;
-use std::collections::{HashSet};
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::path::Path;
 use crate::parse::BarsFound;
+use crate::parse::alignment::Alignment;
+use crate::parse::projection::BarRect;
+use serde::{Serialize, Deserialize, Deserializer};
+use serde::de::{Error, Unexpected};
 
 const TEXT_WIDTH_MULTIPLIER: f64 = 0.6; // characters are how many times wider than they are tall
 const TEXT_GAP: f64 = 0.02; // how many pixels between the end of the text and the start of the field
@@ -11,6 +18,10 @@ const FIELD_START_X: f64 = 0.2;
 
 pub const ALIGNER_DISTANCE_FROM_CORNER: f64 = 0.05;
 
+const TIMING_TRACK_INSET: f64 = 0.035; // between the edge of the page and the aligners, but clear of most printers' margins
+const TIMING_MARK_SPACING: f64 = 0.05;
+const TIMING_TRACK_START: f64 = 0.15; // past where a small photo could run the first mark into the aligner beside it
+
 const BAR_DISTANCE_THRESHOLD: f64 = 0.01;
 
 const TITLE_X: f64 = 0.3;
@@ -24,6 +35,8 @@ const VERTICAL_FIELD_SPACE: f64 = 0.1;
 const BAR_VERTICAL_OFFSET: f64 = 0.03;
 const SEVEN_SEGMENT_DISPLAY_OFFSET: f64 = 0.0;
 
+const MAX_DIGIT_COUNT: u8 = 19; // any more and the number might not fit in a u64
+
 // describes offset from the top left of the digit
 const SEVEN_SEGMENT_BAR_OFFSETS: [(f64, f64, bool); 7] = [ // (x, y, is_horizontal)
     (BAR_WIDTH+BAR_SPACE, 0.0, true), // top
@@ -38,15 +51,31 @@ const SEVEN_SEGMENT_BAR_OFFSETS: [(f64, f64, bool); 7] = [ // (x, y, is_horizont
 
 
 
+/// What a sheet should ask for, before anything has been positioned on the page.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct HighLevelPageDescription {
+    /// Printed at the top of the sheet
     pub document_title: String,
+    /// Laid out top to bottom in this order
+    #[serde(deserialize_with = "deserialize_fields")]
     pub fields: Vec<HighLevelField>,
+    /// Prints extra aligners at the middle of the left, right, and bottom edges, so that one misread aligner doesn't
+    /// stop the sheet from being read, and so the aligners can be checked against each other, which the four corners
+    /// alone can't be. Fields aren't moved out of their way, so keep long fields above them.
+    #[serde(default)]
+    pub edge_fiducials: bool,
+    /// Prints a track of small square timing marks along each edge, so that curled or bent paper can be straightened
+    /// out locally rather than just by its corners
+    #[serde(default)]
+    pub timing_tracks: bool,
 }
 
 impl HighLevelPageDescription {
+    /// Decides where every field and bar goes on the page.
     pub fn layout(&self) -> PageLayout {
         let mut id_generator = BarIdGenerator::new();
-        let mut layout = PageLayout::new(self.document_title.clone());
+        let mut layout = PageLayout::new(self.document_title.clone(), PageGeometry::current(self.edge_fiducials, self.timing_tracks));
 
         let mut current_y = VERTICAL_FIELD_START;
 
@@ -71,35 +100,196 @@ impl HighLevelPageDescription {
     }
 }
 
+/// One question on the sheet.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct HighLevelField {
+    /// How the answer is filled in
     pub kind: HighLevelKind,
+    /// The text printed next to the field, also used to name its result
+    #[serde(deserialize_with = "deserialize_descriptor")]
     pub descriptor: String,
 }
 
+/// How the answer to a field is filled in.
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum HighLevelKind {
+    /// A single bar, filled in for yes
     Boolean,
-    SevenSegmentDisplay(u8), // digit count
+    /// A number written with seven segment digits, holding the digit count
+    SevenSegmentDisplay(#[serde(deserialize_with = "deserialize_digit_count")] u8),
+}
+
+
+
+fn deserialize_fields<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<HighLevelField>, D::Error> {
+    let fields = Vec::<HighLevelField>::deserialize(deserializer)?;
+
+    // results are keyed by descriptor, so they have to be unique
+    let mut seen = HashSet::new();
+    for field in fields.iter() {
+        if !seen.insert(field.descriptor.as_str()) {
+            return Err(D::Error::custom(format!("duplicate descriptor `{}`", field.descriptor)));
+        }
+    }
+
+    Ok(fields)
 }
 
+fn deserialize_descriptor<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let descriptor = String::deserialize(deserializer)?;
+
+    if descriptor.trim().is_empty() {
+        return Err(D::Error::invalid_value(Unexpected::Str(&descriptor), &"a non-empty descriptor"));
+    }
+
+    Ok(descriptor)
+}
 
+fn deserialize_digit_count<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    let digit_count = u8::deserialize(deserializer)?;
+
+    if digit_count == 0 || digit_count > MAX_DIGIT_COUNT {
+        return Err(D::Error::invalid_value(Unexpected::Unsigned(digit_count as u64), &"a digit count between 1 and 19"));
+    }
+
+    Ok(digit_count)
+}
 
+
+
+/// The exact position of everything printed on a sheet, needed both to render it and to read it back.
+#[derive(Serialize, Deserialize)]
 pub struct PageLayout {
     document_title: String,
-    fields: Vec<LayoutEntry>, // FIXME: un-public
+    geometry: PageGeometry,
+    fields: Vec<LayoutEntry>,
     descriptors: Vec<(f64, f64, String)>, // x, y, text
 }
 
-#[derive(Debug)]
-enum LayoutEntry { // TODO: replace this with a Field trait with elements_iter, interpret_found_target, && make not public
+/// Everything about the page that the parser needs to know, and that might change between versions of the layout constants.
+/// It is saved along with the layout so that old sheets can still be read.
+/// All positions and sizes are fractions of the page side, with the origin at the top left.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PageGeometry {
+    /// In inches; the page is square
+    pub document_height: f64,
+    /// The short side of a bar
+    pub bar_width: f64,
+    /// The long side of a bar
+    pub bar_length: f64,
+    /// How far a found bar's center may be from where it was printed
+    pub bar_distance_threshold: f64,
+    /// Top left, top right, bottom right, bottom left
+    pub aligner_centers: [(f64, f64); 4],
+    /// More aligners, away from the corners, that are used along with the corner ones to straighten the page out
+    #[serde(default)]
+    pub extra_fiducials: Vec<(f64, f64)>,
+    /// The corner aligners have one to four dots inside, in the same order as `aligner_centers`, so the corners can be
+    /// told apart however the page is turned or mirrored
+    #[serde(default)]
+    pub coded_aligners: bool,
+    /// Timing marks along the edges of the page, if it has them
+    #[serde(default)]
+    pub timing_tracks: Option<TimingTracks>,
+}
+
+/// A track of square timing marks along each of the four edges of the page, all with their marks at the same places.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TimingTracks {
+    /// How far the center of each track is from its edge
+    pub inset: f64,
+    /// The side of a mark
+    pub mark_size: f64,
+    /// Where the center of each mark is along its track, measured from the top or the left of the page
+    pub positions: Vec<f64>,
+}
+
+impl TimingTracks {
+    fn current() -> TimingTracks {
+        let count = ((1.0 - 2.0*TIMING_TRACK_START) / TIMING_MARK_SPACING).round() as usize + 1;
+
+        TimingTracks {
+            inset: TIMING_TRACK_INSET,
+            mark_size: TIMING_MARK_SIZE,
+            positions: (0..count).map(|i| TIMING_TRACK_START + i as f64 * TIMING_MARK_SPACING).collect(),
+        }
+    }
+
+    /// The centers of the marks on the top, bottom, left, and right tracks, in that order
+    pub(crate) fn mark_centers(&self) -> impl Iterator<Item=(f64, f64)> + '_ {
+        let (near, far) = (self.inset, 1.0 - self.inset);
+
+        let top = self.positions.iter().map(move |&x| (x, near));
+        let bottom = self.positions.iter().map(move |&x| (x, far));
+        let left = self.positions.iter().map(move |&y| (near, y));
+        let right = self.positions.iter().map(move |&y| (far, y));
+
+        top.chain(bottom).chain(left).chain(right)
+    }
+}
+
+impl PageGeometry {
+    fn current(edge_fiducials: bool, timing_tracks: bool) -> PageGeometry {
+        let near = ALIGNER_DISTANCE_FROM_CORNER+ALIGNER_OUTER_RADIUS;
+        let far = 1.0-near;
+
+        // the title is in the way of one at the middle of the top edge
+        let extra_fiducials = if edge_fiducials {
+            vec![(near, 0.5), (far, 0.5), (0.5, far)]
+        } else {
+            Vec::new()
+        };
+
+        PageGeometry {
+            document_height: DOCUMENT_HEIGHT,
+            bar_width: BAR_WIDTH,
+            bar_length: BAR_LENGTH,
+            bar_distance_threshold: BAR_DISTANCE_THRESHOLD,
+            aligner_centers: [(near, near), (far, near), (far, far), (near, far)],
+            extra_fiducials,
+            coded_aligners: true,
+            timing_tracks: if timing_tracks { Some(TimingTracks::current()) } else { None },
+        }
+    }
+
+    /// The centers of every aligner printed on the page, the corner ones first
+    pub(crate) fn fiducial_centers(&self) -> Vec<(f64, f64)> {
+        self.aligner_centers.iter().chain(self.extra_fiducials.iter()).copied().collect()
+    }
+
+    /// How many dots are inside the `i`th aligner of `fiducial_centers`
+    pub(crate) fn aligner_dots(&self, i: usize) -> usize {
+        if self.coded_aligners && i < self.aligner_centers.len() { i + 1 } else { 0 }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum LayoutEntry { // TODO: replace this with a Field trait with elements_iter, interpret_found_target
     Boolean(Bar),
     SevenSegmentDisplay(SevenSegmentDisplay), // this actually consists of bars
 }
 
+impl LayoutEntry {
+    fn bars(&self) -> Vec<&Bar> {
+        match *self {
+            LayoutEntry::Boolean(ref bar) => vec![bar],
+            LayoutEntry::SevenSegmentDisplay(ref n) => n.digits.iter().flat_map(|digit| digit.bars.iter()).collect(),
+        }
+    }
+}
+
 
 
 impl PageLayout {
-    fn new(title: String) -> PageLayout {
-        PageLayout { document_title: title, fields: Vec::new(), descriptors: Vec::new() }
+    fn new(title: String, geometry: PageGeometry) -> PageLayout {
+        PageLayout { document_title: title, geometry, fields: Vec::new(), descriptors: Vec::new() }
+    }
+
+    /// The sizes and aligner positions this layout was made with
+    pub fn geometry(&self) -> &PageGeometry {
+        &self.geometry
     }
 
     fn add_entry(&mut self, entry: LayoutEntry, descriptor: String, x: f64, y: f64) {
@@ -107,6 +297,7 @@ impl PageLayout {
         self.descriptors.push((x, y, descriptor));
     }
 
+    /// Renders the printable sheet.
     pub fn to_svg(&self) -> svg::Document {
         let mut elements = ScanSheetElements::empty();
         elements.add_element(Element { // document title
@@ -114,26 +305,23 @@ impl PageLayout {
             y: TITLE_Y,
             kind: ElementKind::Title(self.document_title.clone()),
         });
-        elements.add_element(Element { // top left
-            x: ALIGNER_DISTANCE_FROM_CORNER,
-            y: ALIGNER_DISTANCE_FROM_CORNER,
-            kind: ElementKind::Aligner,
-        });
-        elements.add_element(Element { // top right
-            x: 1.0-2.0*ALIGNER_OUTER_RADIUS-ALIGNER_DISTANCE_FROM_CORNER,
-            y: ALIGNER_DISTANCE_FROM_CORNER,
-            kind: ElementKind::Aligner,
-        });
-        elements.add_element(Element { // bottom right
-            x: 1.0-2.0*ALIGNER_OUTER_RADIUS-ALIGNER_DISTANCE_FROM_CORNER,
-            y: 1.0-2.0*ALIGNER_OUTER_RADIUS-ALIGNER_DISTANCE_FROM_CORNER,
-            kind: ElementKind::Aligner,
-        });
-        elements.add_element(Element { // bottom left
-            x: ALIGNER_DISTANCE_FROM_CORNER,
-            y: 1.0-2.0*ALIGNER_OUTER_RADIUS-ALIGNER_DISTANCE_FROM_CORNER,
-            kind: ElementKind::Aligner,
-        });
+        if let Some(ref tracks) = self.geometry.timing_tracks {
+            for (x, y) in tracks.mark_centers() {
+                elements.add_element(Element { // positioned by their top left corner too
+                    x: x-tracks.mark_size/2.0,
+                    y: y-tracks.mark_size/2.0,
+                    kind: ElementKind::TimingMark,
+                });
+            }
+        }
+
+        for (i, (x, y)) in self.geometry.fiducial_centers().into_iter().enumerate() {
+            elements.add_element(Element { // aligner elements are positioned by their top left corner
+                x: x-ALIGNER_OUTER_RADIUS,
+                y: y-ALIGNER_OUTER_RADIUS,
+                kind: ElementKind::Aligner { dots: self.geometry.aligner_dots(i) },
+            });
+        }
 
 
         for &(x, y, ref text) in self.descriptors.iter() {
@@ -158,69 +346,210 @@ impl PageLayout {
         elements.to_svg()
     }
 
-    pub fn interpret_targets(&self, targets_found: &BarsFound) -> Result<LayoutResult, LayoutResultError> {
-        // avoid silently double counting bars, hard error instead
-        let mut already_found = HashSet::new();
+    /// Writes the printable sheet as an SVG file.
+    pub fn output_svg_to_file(&self, name: &Path) -> io::Result<()> {
+        svg::save(name, &self.to_svg())
+    }
+
+    /// Where every bar is printed, in layout order, which is also the order their ids were handed out in
+    pub(crate) fn bar_rects(&self) -> Vec<BarRect> {
+        self.fields.iter()
+            .flat_map(|entry| entry.bars())
+            .map(|bar| bar.rect(&self.geometry))
+            .collect()
+    }
 
+    /// Whether the bars are numbered from 0 in layout order, as `bar_rects` and the bars found on a sheet expect. Only
+    /// a layout file edited by hand should fail this.
+    pub(crate) fn bar_ids_in_order(&self) -> bool {
+        self.fields.iter()
+            .flat_map(|entry| entry.bars())
+            .enumerate()
+            .all(|(i, bar)| bar.id.inner == i as u64)
+    }
+
+    /// Works out the value of every field from the bars found on a sheet made with this layout.
+    /// Each field is read on its own, so one badly filled in field doesn't stop the rest from being read.
+    pub fn interpret_targets(&self, targets_found: &BarsFound) -> LayoutResult {
+        let (result, scores) = self.read_fields(targets_found).into_iter()
+            .map(|(descriptor, option, scores)| ((descriptor, option), scores))
+            .unzip();
+
+        // override marks are read on their own, and only the fields they touch are reported
+        let overrides = if targets_found.overrides().is_empty() {
+            Vec::new()
+        } else {
+            self.read_fields(&targets_found.overrides_as_marks()).into_iter()
+                .filter(|(_, option, _)| !matches!(*option, LayoutResultOption::Boolean(false) | LayoutResultOption::Empty))
+                .map(|(descriptor, option, _)| (descriptor, option))
+                .collect()
+        };
+
+        LayoutResult { source: None, result, scores, overrides, alignment: *targets_found.alignment() }
+    }
+
+    fn read_fields(&self, targets_found: &BarsFound) -> Vec<(String, LayoutResultOption, Vec<f64>)> {
         let mut result = Vec::new();
 
-        for entry in self.fields.iter() {
-            match *entry {
-                LayoutEntry::Boolean(ref bar) => {
-                    let is_set = bar.is_set(targets_found, &mut already_found)?;
-                    result.push(LayoutResultOption::Boolean(is_set))
+        for (entry, (_, _, descriptor)) in self.fields.iter().zip(self.descriptors.iter()) {
+            let option = match *entry {
+                LayoutEntry::Boolean(ref bar) => match bar.state(targets_found) {
+                    BarState::Set => LayoutResultOption::Boolean(true),
+                    BarState::Unset => LayoutResultOption::Boolean(false),
+                    BarState::Ambiguous => LayoutResultOption::Ambiguous,
+                    BarState::Erased => LayoutResultOption::Erased,
+                    BarState::Cancelled => LayoutResultOption::Cancelled,
                 },
-                LayoutEntry::SevenSegmentDisplay(ref number) => {
-                    let n = number.as_number(targets_found, &mut already_found)?;
-                    result.push(LayoutResultOption::Number(n));
-                }
-            }
+                LayoutEntry::SevenSegmentDisplay(ref number) => match number.as_number(targets_found) {
+                    Ok(n) => LayoutResultOption::Number(n),
+                    Err(SevenSegmentError::Empty) => LayoutResultOption::Empty,
+                    Err(SevenSegmentError::Invalid { digit, bars }) => LayoutResultOption::Invalid { digit, bars },
+                    Err(SevenSegmentError::Ambiguous) => LayoutResultOption::Ambiguous,
+                    Err(SevenSegmentError::Erased) => LayoutResultOption::Erased,
+                    Err(SevenSegmentError::Cancelled) => LayoutResultOption::Cancelled,
+                },
+            };
+
+            let scores = entry.bars().iter().map(|bar| bar.score(targets_found)).collect();
+
+            result.push((descriptor.clone(), option, scores));
         }
 
-        Ok(LayoutResult { result })
+        result
     }
 }
 
-#[derive(Debug)]
-pub enum LayoutResultError {
-    BarConflictError,
-    SevenSegmentError(SevenSegmentError),
+
+
+/// The values read from one sheet.
+pub struct LayoutResult {
+    source: Option<String>, // usually the name of the image file
+    result: Vec<(String, LayoutResultOption)>, // descriptor, value
+    scores: Vec<Vec<f64>>, // how much of each of the field's bars is dark, for each field of result
+    overrides: Vec<(String, LayoutResultOption)>, // descriptor, value read from override ink, for the fields that have any
+    alignment: Alignment,
 }
 
-impl From<BarConflictError> for LayoutResultError {
-    fn from(_error: BarConflictError) -> LayoutResultError {
-        LayoutResultError::BarConflictError
+impl LayoutResult {
+    /// Where the sheet came from, usually the name of the image file
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
     }
-}
 
-impl From<SevenSegmentError> for LayoutResultError {
-    fn from(error: SevenSegmentError) -> LayoutResultError {
-        LayoutResultError::SevenSegmentError(error)
+    /// Records where the sheet came from, so it shows up in exported results
+    pub fn set_source(&mut self, source: String) {
+        self.source = Some(source);
     }
-}
 
+    /// Each field's descriptor and value, in layout order
+    pub fn fields(&self) -> impl Iterator<Item=(&str, &LayoutResultOption)> {
+        self.result.iter().map(|(descriptor, value)| (descriptor.as_str(), value))
+    }
 
+    /// Each field's descriptor, and how much of each of its bars is dark, from 0 to 1. A seven segment display's bars
+    /// go digit by digit from the left, top bar (a) to middle bar (g).
+    pub fn scores(&self) -> impl Iterator<Item=(&str, &[f64])> {
+        self.result.iter().zip(self.scores.iter()).map(|((descriptor, _), scores)| (descriptor.as_str(), scores.as_slice()))
+    }
 
-pub struct LayoutResult {
-    result: Vec<LayoutResultOption>,
-}
+    /// The fields marked in an override ink, like a teacher's red pen, with the value read from those marks alone
+    pub fn overrides(&self) -> impl Iterator<Item=(&str, &LayoutResultOption)> {
+        self.overrides.iter().map(|(descriptor, value)| (descriptor.as_str(), value))
+    }
 
-impl LayoutResult {
-    pub fn describe_results(&self, page_description: &HighLevelPageDescription) {
-        for (i, (field, result)) in page_description.fields.iter().zip(self.result.iter()).enumerate() {
-            println!("field #{} - '{}' has value {:?}", i, field.descriptor, result);
+    /// How well the fiducials found on the sheet agreed with each other
+    pub fn alignment(&self) -> &Alignment {
+        &self.alignment
+    }
+
+    /// The fields that couldn't be read and should be checked by a person
+    pub fn needs_review(&self) -> impl Iterator<Item=(&str, &LayoutResultOption)> {
+        self.fields().filter(|(_, value)| !value.is_value())
+    }
+
+    /// Writes every field's value to `writer`, a line each, followed by how well the page was aligned
+    pub fn describe_results(&self, mut writer: impl io::Write) -> io::Result<()> {
+        for (i, ((descriptor, result), scores)) in self.result.iter().zip(self.scores.iter()).enumerate() {
+            writeln!(writer, "field #{} - '{}' has value {}", i, descriptor, result)?;
+
+            if !result.is_value() {
+                let scores: Vec<String> = scores.iter().map(|score| format!("{:.2}", score)).collect();
+                writeln!(writer, "field #{} - bar scores {}", i, scores.join(" "))?;
+            }
+        }
+
+        for (descriptor, result) in self.overrides() {
+            writeln!(writer, "override - '{}' has value {}", descriptor, result)?;
         }
+
+        let alignment = &self.alignment;
+        let note = match (alignment.needs_review(), alignment.estimated()) {
+            (true, _) => " (needs review)",
+            (false, true) => " (estimated from three fiducials)",
+            (false, false) => "",
+        };
+        writeln!(writer, "alignment - {} fiducials used, {} left out, reprojection error {:.4}{}", alignment.fiducials_used(),
+            alignment.outliers(), alignment.reprojection_error(), note)?;
+        if alignment.timing_marks_used() > 0 {
+            writeln!(writer, "alignment - {} timing marks used", alignment.timing_marks_used())?;
+        }
+
+        Ok(())
     }
 }
 
+/// What was read from one field.
+///
+/// There is no outcome for a bar claimed by two fields, as there was when marks were matched to the nearest bar: each
+/// bar is now measured in its own rectangle of the page, and a mark that strays onto another field's bar is reported
+/// there, as `Ambiguous` if it covers only part of it, or as `Invalid` if it adds a bar to a digit.
 #[derive(Debug)]
-enum LayoutResultOption {
+pub enum LayoutResultOption {
+    /// From a `HighLevelKind::Boolean`
     Boolean(bool),
+    /// From a `HighLevelKind::SevenSegmentDisplay`
     Number(u64),
+    /// A seven segment display with no bars filled in
+    Empty,
+    /// A seven segment display with a digit that can't be read, either because its bars don't make a digit or because
+    /// it is blank between two filled in digits
+    Invalid {
+        /// Which digit, counting from 0 on the left
+        digit: usize,
+        /// The bars filled in; bit 6 is the top bar (a) and bit 0 is the middle bar (g)
+        bars: usize,
+    },
+    /// A bar is dark enough that it may have been filled in, but not dark enough to be sure
+    Ambiguous,
+    /// A bar was filled in, then rubbed out
+    Erased,
+    /// A bar was crossed out
+    Cancelled,
 }
 
+impl LayoutResultOption {
+    /// Returns true for `Boolean` and `Number`, the outcomes that can be accepted without review
+    pub fn is_value(&self) -> bool {
+        matches!(*self, LayoutResultOption::Boolean(_) | LayoutResultOption::Number(_))
+    }
+}
 
-#[derive(Debug)]
+impl fmt::Display for LayoutResultOption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LayoutResultOption::Boolean(b) => write!(f, "{}", b),
+            LayoutResultOption::Number(n) => write!(f, "{}", n),
+            LayoutResultOption::Empty => write!(f, "empty"),
+            LayoutResultOption::Invalid { digit, bars } => write!(f, "invalid (digit {} has bars {:07b})", digit, bars),
+            LayoutResultOption::Ambiguous => write!(f, "ambiguous"),
+            LayoutResultOption::Erased => write!(f, "erased"),
+            LayoutResultOption::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
 struct SevenSegmentDisplay {
     digits: Vec<SevenSegmentDigit>,
 }
@@ -263,22 +592,26 @@ impl SevenSegmentDisplay {
         })
     }
 
-    fn as_number(&self, targets_found: &BarsFound, already_found: &mut HashSet<BarId>) -> Result<u64, SevenSegmentError> {
-        // returns None if no segments are filled, or we have an invalid digit
+    fn as_number(&self, targets_found: &BarsFound) -> Result<u64, SevenSegmentError> {
+        // returns Empty if no segments are filled, or Invalid if we have an invalid digit
         // we are looking at these digits from right to left
 
         let mut sum = 0;
         let mut power_of_ten = 1;
 
-        let mut has_seen_empty = false; // there's no problem with seeing empty if the number has finished
+        let mut first_empty = None; // there's no problem with seeing empty if the number has finished
         let mut all_are_empty = true;
 
-        for digit in self.digits.iter().rev() {
-            match digit.get_digit(targets_found, already_found) {
-                Ok(_) if has_seen_empty => return Err(SevenSegmentError::Empty), // this situation looks like: 5523_23 or something
-                Err(SevenSegmentError::Empty) => has_seen_empty = true, // something like _23
-                Err(SevenSegmentError::Invalid(n)) => return Err(SevenSegmentError::Invalid(n)),
-                Err(SevenSegmentError::BarConflict) => return Err(SevenSegmentError::BarConflict),
+        for (i, digit) in self.digits.iter().enumerate().rev() {
+            match digit.get_digit(targets_found) {
+                Ok(_) if first_empty.is_some() => { // this situation looks like: 5523_23 or something
+                    return Err(SevenSegmentError::Invalid { digit: first_empty.unwrap(), bars: 0 });
+                },
+                Err(SevenSegmentError::Empty) => first_empty = Some(i), // something like _23
+                Err(SevenSegmentError::Invalid { bars, .. }) => return Err(SevenSegmentError::Invalid { digit: i, bars }),
+                Err(SevenSegmentError::Ambiguous) => return Err(SevenSegmentError::Ambiguous),
+                Err(SevenSegmentError::Erased) => return Err(SevenSegmentError::Erased),
+                Err(SevenSegmentError::Cancelled) => return Err(SevenSegmentError::Cancelled),
                 Ok(d) => {
                     all_are_empty = false;
                     sum += d*power_of_ten;
@@ -297,7 +630,7 @@ impl SevenSegmentDisplay {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 struct SevenSegmentDigit {
     bars: Vec<Bar>, // specified in the order mentioned (a..f) in https://en.wikipedia.org/wiki/Seven-segment_display#Displaying_letters
 }
@@ -314,12 +647,18 @@ impl SevenSegmentDigit {
         SevenSegmentDigit { bars }
     }
 
-    fn get_digit(&self, targets_found: &BarsFound, already_found: &mut HashSet<BarId>) -> Result<u64, SevenSegmentError> {
+    fn get_digit(&self, targets_found: &BarsFound) -> Result<u64, SevenSegmentError> {
         use SevenSegmentError::*;
         
         let mut bars_set = 0; // default value
         for (i, bar) in self.bars.iter().rev().enumerate() {
-            let is_set = bar.is_set(targets_found, already_found)? as usize;
+            let is_set = match bar.state(targets_found) {
+                BarState::Set => 1,
+                BarState::Unset => 0,
+                BarState::Ambiguous => return Err(Ambiguous),
+                BarState::Erased => return Err(Erased),
+                BarState::Cancelled => return Err(Cancelled),
+            };
             bars_set |= is_set << i;
         }
 
@@ -335,20 +674,22 @@ impl SevenSegmentDigit {
             0b1110000 => Ok(7),
             0b1111111 => Ok(8),
             0b1111011 | 0b1110011 => Ok(9), // alternate repr of 9 without bottom bar
-            _ => Err(Invalid(bars_set)),
+            _ => Err(Invalid { digit: 0, bars: bars_set }), // as_number knows which digit this is
         }
     }
 }
 
 #[derive(Debug)]
-pub enum SevenSegmentError {
+enum SevenSegmentError {
     Empty, // just a digit with no bars set
-    Invalid(usize), // an invalid set of bars filled
-    BarConflict, // a bar was found that we thought belonged to the display, but apparently it is owned by another target (bad error)
+    Invalid { digit: usize, bars: usize }, // an invalid set of bars filled, or a gap in the number
+    Ambiguous, // a bar is neither clearly filled in nor clearly empty
+    Erased, // a bar was filled in and rubbed out
+    Cancelled, // a bar was crossed out
 }
 
-#[derive(Debug)]
-pub struct Bar { // FIXME: unpublic
+#[derive(Debug, Serialize, Deserialize)]
+struct Bar {
     x: f64,
     y: f64,
     is_horizontal: bool,
@@ -362,30 +703,29 @@ impl Bar {
         Bar { x, y, is_horizontal, id }
     }
 
-    fn mean_position(&self) -> (f64, f64) {
-        // targets_found iterates over the mean position of the image targets, we have to get the mean position out here too
-        let (base, height) = if self.is_horizontal { (BAR_LENGTH, BAR_WIDTH) } else { (BAR_WIDTH, BAR_LENGTH ) };
-
-        (self.x + base/2.0, self.y + height/2.0)
+    fn size(&self, geometry: &PageGeometry) -> (f64, f64) {
+        if self.is_horizontal {
+            (geometry.bar_length, geometry.bar_width)
+        } else {
+            (geometry.bar_width, geometry.bar_length)
+        }
     }
 
-    fn is_set(&self, targets_found: &BarsFound, already_found: &mut HashSet<BarId>) -> Result<bool, BarConflictError> {
-
-        let (mean_x, mean_y) = self.mean_position();
+    fn rect(&self, geometry: &PageGeometry) -> BarRect {
+        let (base, height) = self.size(geometry);
 
-        for &(target_x, target_y) in targets_found.bars.iter() {
-            let distance = (target_x-mean_x).hypot(target_y-mean_y);
+        BarRect { left: self.x, top: self.y, right: self.x + base, bottom: self.y + height }
+    }
 
-            if distance < BAR_DISTANCE_THRESHOLD {
-                if already_found.contains(&self.id) {
-                    return Err(BarConflictError)
-                } else {
-                    return Ok(true);
-                }
-            } // else: this isn't even relavant to this target
-        }
+    /// How much of the bar is dark, from 0 to 1
+    fn score(&self, targets_found: &BarsFound) -> f64 {
+        // bars are measured in layout order, which is the order their ids were handed out in. A bar that wasn't
+        // measured, because the sheet was read with another layout, reads as empty.
+        targets_found.scores().get(self.id.inner as usize).copied().unwrap_or(0.0)
+    }
 
-        Ok(false)
+    fn state(&self, targets_found: &BarsFound) -> BarState {
+        targets_found.states().get(self.id.inner as usize).copied().unwrap_or(BarState::Unset)
     }
 
     fn to_element(&self) -> Element {
@@ -397,17 +737,23 @@ impl Bar {
     }
 }
 
-#[derive(Copy, Clone)]
-struct BarConflictError;
-
-impl From<BarConflictError> for SevenSegmentError {
-    fn from(_error: BarConflictError) -> SevenSegmentError {
-        SevenSegmentError::BarConflict
-    }
+/// How a bar reads, from how much of it is dark.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BarState {
+    /// Filled in
+    Set,
+    /// Empty
+    Unset,
+    /// Neither clearly filled in nor clearly empty, like a half filled bar, a smudge, or light pencil
+    Ambiguous,
+    /// Filled in, then rubbed out, leaving marks only a little darker than the paper
+    Erased,
+    /// Filled in or not, then crossed out with a scribble reaching well past the bar
+    Cancelled,
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
-pub struct BarId {
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
+struct BarId {
     inner: u64,
 }
 
@@ -427,4 +773,69 @@ impl BarIdGenerator {
         self.inner += 1;
         ret
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A yes or no question followed by a one digit number
+    fn layout() -> PageLayout {
+        HighLevelPageDescription {
+            document_title: "test".to_string(),
+            fields: vec![
+                HighLevelField { kind: HighLevelKind::Boolean, descriptor: "yes".to_string() },
+                HighLevelField { kind: HighLevelKind::SevenSegmentDisplay(1), descriptor: "number".to_string() },
+            ],
+            edge_fiducials: false,
+            timing_tracks: false,
+        }.layout()
+    }
+
+    fn states(set: &[bool]) -> Vec<BarState> {
+        set.iter().map(|&set| if set { BarState::Set } else { BarState::Unset }).collect()
+    }
+
+    #[test]
+    fn fields_are_read_from_their_own_bars() {
+        // the boolean, then a 7, which is the top and both right bars
+        let found = BarsFound::from_states(states(&[true, true, true, true, false, false, false, false]));
+        let result = layout().interpret_targets(&found);
+        let fields: Vec<String> = result.fields().map(|(_, value)| value.to_string()).collect();
+
+        assert_eq!(fields, ["true", "7"]);
+    }
+
+    #[test]
+    fn bars_missing_from_the_sheet_read_as_empty() {
+        // as if the sheet had been read with a layout with just the boolean
+        let found = BarsFound::from_states(states(&[true]));
+        let result = layout().interpret_targets(&found);
+        let fields: Vec<String> = result.fields().map(|(_, value)| value.to_string()).collect();
+
+        assert_eq!(fields, ["true", "empty"]);
+        assert!(result.scores().all(|(descriptor, scores)| descriptor == "yes" || scores.iter().all(|&score| score == 0.0)));
+    }
+
+    #[test]
+    fn bar_ids_follow_layout_order() {
+        let mut layout = layout();
+        assert!(layout.bar_ids_in_order());
+
+        if let LayoutEntry::Boolean(ref mut bar) = layout.fields[0] {
+            bar.id = BarId { inner: 100 };
+        }
+        assert!(!layout.bar_ids_in_order());
+    }
+
+    #[test]
+    fn erased_bars_are_left_for_review() {
+        let mut found = states(&[false; 8]);
+        found[0] = BarState::Erased;
+        found[4] = BarState::Erased;
+        let result = layout().interpret_targets(&BarsFound::from_states(found));
+        let fields: Vec<String> = result.fields().map(|(_, value)| value.to_string()).collect();
+
+        assert_eq!(fields, ["erased", "erased"]);
+        assert_eq!(result.needs_review().count(), 2);
+    }
+}
@@ -0,0 +1,467 @@
+use crate::make::scan_sheet_layout::PageGeometry;
+use crate::parse::homography::{Homography, combinations, kept};
+use crate::parse::page_map::PageMap;
+use crate::parse::scan_error::ScanError;
+use crate::parse::target_mesh::AlignerCandidate;
+
+const MATCH_DISTANCE: f64 = 0.05; // how far from a fiducial, as a fraction of the page side, an aligner can be found and still be it
+const INLIER_DISTANCE: f64 = 0.01; // fiducials found further than this from where the fit puts them are left out of it
+
+/// How well the fiducials found on a sheet agree with each other, which says how close to where they were looked for
+/// its bars really are. Four fiducials fix a perspective transformation exactly, so the corner aligners alone always
+/// agree: the error and outliers are only measured, and a sheet only flagged for review, on layouts printed with
+/// `edge_fiducials`.
+#[derive(Clone, Copy, Debug)]
+pub struct Alignment {
+    fiducials_used: usize,
+    outliers: usize,
+    reprojection_error: f64,
+    needs_review: bool,
+    estimated: bool,
+    timing_marks_used: usize,
+}
+
+impl Alignment {
+    /// How many of the page's fiducials were found and used to straighten it out, counting the corner aligners
+    pub fn fiducials_used(&self) -> usize {
+        self.fiducials_used
+    }
+
+    /// How many aligners were found near a fiducial, but left out for disagreeing with the rest
+    pub fn outliers(&self) -> usize {
+        self.outliers
+    }
+
+    /// The root mean square distance between where the fiducials used were printed and where they were found, once
+    /// mapped back onto the page, as a fraction of the page side
+    pub fn reprojection_error(&self) -> f64 {
+        self.reprojection_error
+    }
+
+    /// True if the fiducials disagree enough that bars could be read from the wrong place, because one of those used
+    /// is found more than half of `bar_distance_threshold` from where the rest put it
+    pub fn needs_review(&self) -> bool {
+        self.needs_review
+    }
+
+    /// True if only three fiducials were found, say because a thumb was over a corner, so the page was straightened
+    /// out as if the photo had no perspective. Bars far from the fiducials found may be a little off.
+    pub fn estimated(&self) -> bool {
+        self.estimated
+    }
+
+    /// How many of the page's timing marks were found and used to straighten it out locally, if it has any
+    pub fn timing_marks_used(&self) -> usize {
+        self.timing_marks_used
+    }
+}
+
+#[cfg(test)]
+impl Alignment {
+    /// Four corners found exactly where they were printed
+    pub(crate) fn exact() -> Alignment {
+        Alignment { fiducials_used: 4, outliers: 0, reprojection_error: 0.0, needs_review: false, estimated: false, timing_marks_used: 0 }
+    }
+}
+
+/// Works out where the page is in the image from `aligners`, the aligners found in the image, biggest first, and
+/// `targets`, the centers of the other targets found, which may include timing marks. Everything is in pixels.
+pub(crate) fn align(aligners: &[AlignerCandidate], targets: &[(f64, f64)], geometry: &PageGeometry) -> Result<(PageMap, Alignment), ScanError> {
+    let (page_to_image, alignment) = fit_fiducials(aligners, geometry)?;
+
+    match geometry.timing_tracks {
+        Some(ref tracks) => {
+            let (page_map, timing_marks_used) = PageMap::with_timing_marks(page_to_image, tracks, targets);
+            Ok((page_map, Alignment { timing_marks_used, ..alignment }))
+        },
+        None => Ok((PageMap::from_homography(page_to_image), alignment)),
+    }
+}
+
+/// The transformation taking the page's fiducials to `aligners`
+fn fit_fiducials(aligners: &[AlignerCandidate], geometry: &PageGeometry) -> Result<(Homography, Alignment), ScanError> {
+    if aligners.len() < 3 {
+        return Err(ScanError::MissingAligners { found: aligners.len() });
+    }
+
+    let fiducials = geometry.fiducial_centers();
+    let candidates: Vec<(f64, f64)> = aligners.iter().map(|aligner| aligner.center).collect();
+    let candidates = &candidates[..];
+
+    // if the corners can be told apart by their dots, there's no need to guess which is which from where they are, but
+    // a dot too many or too few can pair the wrong corners, so the guess is still made to check them against
+    let identified = if geometry.coded_aligners { identify_corners(aligners, geometry) } else { None };
+    let guess = first_guess(candidates, geometry, &fiducials);
+    let matched = |page_to_image: &Homography| match_fiducials(&fiducials, candidates, page_to_image).len();
+
+    let mut page_to_image = match (identified, guess) {
+        (Some(identified), Some(guess)) if matched(&guess) > matched(&identified) => guess,
+        (Some(identified), _) => identified, // a guess can't mirror the page, so it may only line up as many by turning it
+        (None, Some(guess)) => guess,
+        (None, None) => return Err(ScanError::DegenerateHomography),
+    };
+    let mut alignment = None;
+
+    // the second time around, fiducials the first guess was too far off to match get picked up
+    for _ in 0..2 {
+        let (from, to): (Vec<_>, Vec<_>) = match_fiducials(&fiducials, candidates, &page_to_image).into_iter().unzip();
+
+        if from.len() == 3 {
+            // the rest of the page can only be placed by assuming there's no perspective
+            let estimate = Homography::affine_from_correspondences(&from, &to).ok_or(ScanError::DegenerateHomography)?;
+            let alignment = Alignment { fiducials_used: 3, outliers: 0, reprojection_error: 0.0, needs_review: false, estimated: true, timing_marks_used: 0 };
+
+            return Ok((estimate, alignment));
+        } else if from.len() < 3 {
+            return Err(ScanError::MissingAligners { found: from.len() });
+        }
+
+        let (fit, inliers) = Homography::fit_robust(&from, &to, INLIER_DISTANCE).ok_or(ScanError::DegenerateHomography)?;
+
+        let (used_from, used_to) = (kept(&from, &inliers), kept(&to, &inliers));
+        let reprojection_error = fit.reprojection_error(&used_from, &used_to);
+        // the fit spreads a bend in the page over every fiducial, so the one it's worst at says more than the average
+        let worst_error = used_from.iter().zip(used_to.iter())
+            .map(|(&from, &to)| fit.reprojection_error(&[from], &[to]))
+            .fold(0.0, f64::max);
+
+        page_to_image = fit;
+        alignment = Some(Alignment {
+            fiducials_used: used_from.len(),
+            outliers: from.len() - used_from.len(),
+            reprojection_error,
+            needs_review: worst_error > geometry.bar_distance_threshold / 2.0,
+            estimated: false,
+            timing_marks_used: 0,
+        });
+    }
+
+    Ok((page_to_image, alignment.expect("we fit at least once")))
+}
+
+/// The transformation taking the corner aligners to the aligners with their dots, if at least three of them are found.
+/// It may turn or mirror the page any which way. Corners whose dots more than one aligner has are left out, since a dot
+/// missed or a speck of dirt can make one corner look like another.
+fn identify_corners(aligners: &[AlignerCandidate], geometry: &PageGeometry) -> Option<Homography> {
+    let (from, to): (Vec<_>, Vec<_>) = geometry.aligner_centers.iter().enumerate()
+        .filter_map(|(i, &corner)| {
+            let dots = geometry.aligner_dots(i);
+            match *aligners.iter().filter(|aligner| aligner.dots == dots).collect::<Vec<_>>() {
+                [aligner] => Some((corner, aligner.center)),
+                _ => None,
+            }
+        })
+        .unzip();
+
+    let page_to_image = match from.len() {
+        4 => Homography::from_correspondences(&from, &to)?,
+        3 => Homography::affine_from_correspondences(&from, &to)?,
+        _ => return None,
+    };
+
+    // two corners swapping dots twists the page through itself
+    if is_convex(&[(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)].map(|corner| page_to_image.map(corner))) {
+        Some(page_to_image)
+    } else {
+        None
+    }
+}
+
+/// Whether the polygon with `corners` in order goes around the same way at each of them, either way
+fn is_convex(corners: &[(f64, f64)]) -> bool {
+    let turns: Vec<f64> = (0..corners.len())
+        .map(|i| {
+            let (a, b, c) = (corners[i], corners[(i + 1) % corners.len()], corners[(i + 2) % corners.len()]);
+            (b.0 - a.0)*(c.1 - b.1) - (b.1 - a.1)*(c.0 - b.0)
+        })
+        .collect();
+
+    turns.iter().all(|&turn| turn > 0.0) || turns.iter().all(|&turn| turn < 0.0)
+}
+
+/// A transformation close enough to tell which fiducial each candidate is. The outermost candidates are usually the
+/// corner aligners, but one can be hidden, so every way of pairing three candidates with three fiducials is tried too.
+/// The guess that lines up the most fiducials with candidates wins, and among those the one that distorts the page least.
+fn first_guess(candidates: &[(f64, f64)], geometry: &PageGeometry, fiducials: &[(f64, f64)]) -> Option<Homography> {
+    let mut guesses = Vec::new();
+
+    if candidates.len() >= 4 {
+        guesses.extend(Homography::from_correspondences(&geometry.aligner_centers, &outermost(candidates)));
+    }
+
+    for page_points in combinations(fiducials.len(), 3) {
+        let from: Vec<_> = page_points.iter().map(|&i| fiducials[i]).collect();
+
+        for found in combinations(candidates.len(), 3) {
+            for [i, j, k] in [[0, 1, 2], [0, 2, 1], [1, 0, 2], [1, 2, 0], [2, 0, 1], [2, 1, 0]] {
+                let to = [candidates[found[i]], candidates[found[j]], candidates[found[k]]];
+                guesses.extend(Homography::affine_from_correspondences(&from, &to));
+            }
+        }
+    }
+
+    guesses.into_iter()
+        .filter_map(|guess| Some((match_fiducials(fiducials, candidates, &guess).len(), distortion(&guess)?, guess)))
+        .max_by(|a, b| a.0.cmp(&b.0).then(b.1.total_cmp(&a.1)))
+        .map(|(_, _, guess)| guess)
+}
+
+/// How far `page_to_image` is from just turning and scaling the middle of the page, with no rotation scoring zero.
+/// Returns None if it mirrors the page, which a photo of it never does.
+fn distortion(page_to_image: &Homography) -> Option<f64> {
+    let center = page_to_image.map((0.5, 0.5));
+    let right = page_to_image.map((0.6, 0.5));
+    let down = page_to_image.map((0.5, 0.6));
+
+    // where the page's axes end up, as the columns of a matrix
+    let (a, c) = (right.0 - center.0, right.1 - center.1);
+    let (b, d) = (down.0 - center.0, down.1 - center.1);
+
+    let determinant = a*d - b*c;
+    if determinant.is_nan() || determinant <= 0.0 {
+        return None;
+    }
+
+    // the closest rotation and scaling is [[p, -q], [q, p]]
+    let (p, q) = ((a + d) / 2.0, (c - b) / 2.0);
+    let stretch = ((a - p).powi(2) + (b + q).powi(2) + (c - q).powi(2) + (d - p).powi(2)).sqrt() / p.hypot(q);
+
+    Some(stretch + q.atan2(p).abs())
+}
+
+/// Pairs each fiducial with the closest unclaimed candidate, if one is near where `page_to_image` puts it
+fn match_fiducials(fiducials: &[(f64, f64)], candidates: &[(f64, f64)], page_to_image: &Homography) -> Vec<((f64, f64), (f64, f64))> {
+    let on_page: Vec<(f64, f64)> = candidates.iter().map(|&c| page_to_image.map_inverse(c)).collect();
+    let mut claimed = vec![false; candidates.len()];
+
+    let mut pairs = Vec::new();
+
+    for &(x, y) in fiducials.iter() {
+        let closest = on_page.iter().enumerate()
+            .filter(|&(i, _)| !claimed[i])
+            .map(|(i, &(cx, cy))| (i, (cx - x).hypot(cy - y)))
+            .filter(|&(_, distance)| distance < MATCH_DISTANCE)
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+
+        if let Some((i, _)) = closest {
+            claimed[i] = true;
+            pairs.push(((x, y), candidates[i]));
+        }
+    }
+
+    pairs
+}
+
+/// The candidates furthest towards the top left, top right, bottom right, and bottom left
+fn outermost(candidates: &[(f64, f64)]) -> Vec<(f64, f64)> {
+    let mut centers = candidates.to_vec();
+
+    vec![
+        remove_max_by(&mut centers, |&(x0, y0), &(x1, y1)| x0+y0 < x1+y1), // top left
+        remove_max_by(&mut centers, |&(x0, y0), &(x1, y1)| x0-y0 > x1-y1), // top right
+        remove_max_by(&mut centers, |&(x0, y0), &(x1, y1)| x0+y0 > x1+y1), // bottom right
+        remove_max_by(&mut centers, |&(x0, y0), &(x1, y1)| x0-y0 < x1-y1), // bottom left
+    ]
+}
+
+fn remove_max_by<T>(vec: &mut Vec<T>, greater_than: impl Fn(&T, &T) -> bool) -> T {
+    let mut max_index = 0;
+    let mut max = &vec[0];
+    for (i, k) in vec.iter().enumerate().skip(1) {
+        if greater_than(k, max) {
+            max = k;
+            max_index = i;
+        }
+    }
+
+    vec.remove(max_index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NEAR: f64 = 0.05;
+    const FAR: f64 = 0.95;
+
+    fn geometry(edge_fiducials: bool) -> PageGeometry {
+        PageGeometry {
+            document_height: 8.5,
+            bar_width: 0.01,
+            bar_length: 0.04,
+            bar_distance_threshold: 0.01,
+            aligner_centers: [(NEAR, NEAR), (FAR, NEAR), (FAR, FAR), (NEAR, FAR)],
+            extra_fiducials: if edge_fiducials { vec![(NEAR, 0.5), (FAR, 0.5), (0.5, FAR)] } else { Vec::new() },
+            coded_aligners: false,
+            timing_tracks: None,
+        }
+    }
+
+    /// A photo of a page, a little turned and in perspective, 1000 pixels across
+    fn page_to_photo() -> Homography {
+        Homography::from_correspondences(
+            &[(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)],
+            &[(120.0, 80.0), (910.0, 130.0), (870.0, 1040.0), (60.0, 990.0)],
+        ).unwrap()
+    }
+
+    /// A photo of a page with no perspective, just turned, stretched, and sheared
+    fn page_to_flat_photo() -> Homography {
+        Homography::from_matrix([[780.0, -90.0, 140.0], [70.0, 830.0, 60.0], [0.0, 0.0, 1.0]]).unwrap()
+    }
+
+    /// The aligners found at `fiducials` in the photo, biggest first
+    fn found(fiducials: &[(f64, f64)]) -> Vec<AlignerCandidate> {
+        found_in(&page_to_photo(), fiducials)
+    }
+
+    fn found_in(photo: &Homography, fiducials: &[(f64, f64)]) -> Vec<AlignerCandidate> {
+        fiducials.iter().map(|&p| AlignerCandidate { center: photo.map(p), dots: 0 }).collect()
+    }
+
+    /// The corner aligners found in `photo` of a page with coded aligners, with how many dots each is seen to have
+    fn coded_in(photo: &Homography, geometry: &PageGeometry, dots: [usize; 4]) -> Vec<AlignerCandidate> {
+        geometry.aligner_centers.iter().zip(dots)
+            .map(|(&p, dots)| AlignerCandidate { center: photo.map(p), dots })
+            .collect()
+    }
+
+    /// `page_to_photo` after turning or mirroring the page by `page_to_page`
+    fn moved(page_to_page: [[f64; 3]; 3]) -> Homography {
+        Homography::from_matrix(page_to_page).unwrap().then(&page_to_photo())
+    }
+
+    fn assert_close(page_map: &PageMap, point: (f64, f64)) {
+        assert_close_in(&page_to_photo(), page_map, point);
+    }
+
+    fn assert_close_in(photo: &Homography, page_map: &PageMap, point: (f64, f64)) {
+        let (x, y) = page_map.map(point);
+        let (true_x, true_y) = photo.map(point);
+        assert!((x - true_x).hypot(y - true_y) < 0.01, "{:?} is at {:?}, not {:?}", point, (x, y), (true_x, true_y));
+    }
+
+    #[test]
+    fn four_corners_always_agree() {
+        let geometry = geometry(false);
+        let mut aligners = found(&geometry.fiducial_centers());
+        aligners[2].center.0 += 15.0; // off by more than the bars are wide
+
+        let (_, alignment) = align(&aligners, &[], &geometry).unwrap();
+        assert_eq!((alignment.fiducials_used(), alignment.outliers()), (4, 0));
+        assert!(alignment.reprojection_error() < 1e-9 && !alignment.needs_review());
+    }
+
+    #[test]
+    fn edge_fiducials_catch_an_outlier() {
+        let geometry = geometry(true);
+        let mut aligners = found(&geometry.fiducial_centers());
+        aligners[5].center.1 += 25.0; // the right edge aligner, about 0.025 of the page off
+
+        let (page_map, alignment) = align(&aligners, &[], &geometry).unwrap();
+        assert_eq!((alignment.fiducials_used(), alignment.outliers()), (6, 1));
+        assert!(alignment.reprojection_error() < 1e-6 && !alignment.needs_review());
+        assert_close(&page_map, (0.5, 0.5));
+        assert_close(&page_map, (0.9, 0.5));
+    }
+
+    /// The aligners found on a page that bulges out by `by` at the middle of its edges
+    fn bulging(geometry: &PageGeometry, by: f64) -> Vec<AlignerCandidate> {
+        let fiducials: Vec<_> = geometry.fiducial_centers().into_iter()
+            .enumerate()
+            .map(|(i, (x, y))| match i {
+                4 => (x - by, y),
+                5 => (x + by, y),
+                6 => (x, y + by),
+                _ => (x, y),
+            })
+            .collect();
+
+        found(&fiducials)
+    }
+
+    #[test]
+    fn disagreeing_fiducials_need_review() {
+        let geometry = geometry(true);
+
+        // not far enough for any aligner to be left out, but far enough to put bars in the wrong place
+        let (_, alignment) = align(&bulging(&geometry, 0.009), &[], &geometry).unwrap();
+        assert_eq!((alignment.fiducials_used(), alignment.outliers()), (7, 0));
+        assert!(alignment.needs_review());
+
+        let (_, alignment) = align(&bulging(&geometry, 0.003), &[], &geometry).unwrap();
+        assert_eq!((alignment.fiducials_used(), alignment.outliers()), (7, 0));
+        assert!(alignment.reprojection_error() > 0.0 && !alignment.needs_review());
+    }
+
+    #[test]
+    fn three_aligners_are_enough() {
+        let geometry = geometry(false);
+        let photo = page_to_flat_photo();
+
+        for hidden in 0..4 {
+            let fiducials: Vec<_> = geometry.fiducial_centers().into_iter().enumerate()
+                .filter(|&(i, _)| i != hidden)
+                .map(|(_, p)| p)
+                .collect();
+
+            let (page_map, alignment) = align(&found_in(&photo, &fiducials), &[], &geometry).unwrap();
+            assert!(alignment.estimated());
+            assert_eq!(alignment.fiducials_used(), 3);
+            for point in [(0.5, 0.5), (0.1, 0.9), (0.9, 0.1), geometry.aligner_centers[hidden]] {
+                assert_close_in(&photo, &page_map, point);
+            }
+        }
+    }
+
+    #[test]
+    fn two_aligners_are_not() {
+        let geometry = geometry(false);
+        match align(&found(&geometry.aligner_centers[..2]), &[], &geometry) {
+            Err(ScanError::MissingAligners { found: 2 }) => {},
+            other => panic!("expected missing aligners, got {:?}", other.map(|(_, alignment)| alignment)),
+        }
+    }
+
+    const MIRRORED: [[f64; 3]; 3] = [[-1.0, 0.0, 1.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    const QUARTER_TURN: [[f64; 3]; 3] = [[0.0, -1.0, 1.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]];
+    const UPRIGHT: [[f64; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+    fn coded_geometry() -> PageGeometry {
+        PageGeometry { coded_aligners: true, ..geometry(false) }
+    }
+
+    fn assert_reads(photo: &Homography, dots: [usize; 4]) {
+        let geometry = coded_geometry();
+        let (page_map, alignment) = align(&coded_in(photo, &geometry, dots), &[], &geometry).unwrap();
+
+        assert_eq!(alignment.fiducials_used(), 4);
+        for point in [(0.5, 0.5), (0.2, 0.3), (0.8, 0.6)] {
+            assert_close_in(photo, &page_map, point);
+        }
+    }
+
+    #[test]
+    fn dots_tell_turned_and_mirrored_pages_apart() {
+        for page_to_page in [UPRIGHT, MIRRORED, QUARTER_TURN] {
+            assert_reads(&moved(page_to_page), [1, 2, 3, 4]);
+        }
+    }
+
+    #[test]
+    fn a_dot_too_many_or_too_few_falls_back_on_where_the_aligners_are() {
+        let upright = moved(UPRIGHT);
+
+        assert_reads(&upright, [1, 2, 2, 4]); // a dot missed
+        assert_reads(&upright, [2, 2, 3, 4]); // a speck of dirt in the hole
+        assert_reads(&upright, [2, 1, 3, 4]); // both, so two corners swap
+        assert_reads(&upright, [0, 0, 0, 0]); // too small to see any
+    }
+
+    #[test]
+    fn one_corner_with_the_wrong_dots_is_left_out() {
+        // three corners are enough to tell which way the page is turned
+        assert_reads(&moved(QUARTER_TURN), [1, 2, 3, 5]);
+        assert_reads(&moved(MIRRORED), [1, 0, 3, 4]);
+    }
+}
@@ -1,5 +1,9 @@
 use crate::parse::image::{Image};
+use crate::parse::scan_options::{ScanOptions, Threshold};
 
+use rayon::prelude::*;
+
+#[derive(Clone)]
 pub struct BooleanMatrix {
     data: Vec<bool>,
     width: usize,
@@ -28,20 +32,34 @@ impl BooleanMatrix {
     }
 
     /// Produces a boolean matrix with true corresponding to a dark color, and false meaning light.
-    pub fn from_image(image: &Image, dark_threshold: u8) -> BooleanMatrix {
-        let mut matrix = BooleanMatrix::all_false(image.base, image.height);
-
-        for y in 0..image.height {
-            for x in 0..image.base {
-                let color = image.get_color(x, y);
+    /// Template colored pixels are treated as paper if the options ask for it, and pixels in a colored ink class are
+    /// judged by how light they look rather than by their brightest channel, so bright red pen still counts as dark.
+    pub fn from_image(image: &Image, options: &ScanOptions) -> BooleanMatrix {
+        let (width, height) = (image.base, image.height);
+        let levels: Vec<u8> = (0..width*height)
+            .map(|i| image.get_color(i % width, i / width))
+            .map(|color| match options.template_dropout {
+                Some(ref dropout) if dropout.is_template(color) => 255,
+                _ if options.ink_classes.iter().any(|ink| ink.is_colored() && ink.matches(color)) => color.gray(),
+                _ => color.brightness(),
+            })
+            .collect();
 
-                if color.is_darker_than(dark_threshold) {
-                    matrix.set(x, y);
-                }
-            }
-        }
+        let data = match options.threshold {
+            Threshold::Fixed(level) => levels.iter().map(|&v| v < level).collect(),
+            Threshold::Otsu => {
+                let level = otsu_level(&levels);
+                levels.iter().map(|&v| v <= level).collect()
+            },
+            Threshold::MeanOfWindow { window, offset } => {
+                local_threshold(&levels, width, height, window, |v, mean, _| v < mean - offset)
+            },
+            Threshold::Sauvola { window, k } => {
+                local_threshold(&levels, width, height, window, |v, mean, deviation| v < mean * (1.0 + k*(deviation/128.0 - 1.0)))
+            },
+        };
 
-        matrix
+        BooleanMatrix { data, width, height }
     }
 
     /// Produces an image with true corresponding to black, and false meaning white
@@ -62,4 +80,192 @@ impl BooleanMatrix {
     fn get_index(&self, x: usize, y: usize) -> usize {
         y*self.width + x
     }
-}
\ No newline at end of file
+}
+/// The level that best splits `levels` into a dark and a light class, by maximizing the variance between the classes.
+/// Levels at or below it are dark.
+fn otsu_level(levels: &[u8]) -> u8 {
+    let mut histogram = [0u64; 256];
+    for &v in levels.iter() {
+        histogram[v as usize] += 1;
+    }
+
+    let total = levels.len() as f64;
+    let total_sum: f64 = histogram.iter().enumerate().map(|(v, &n)| v as f64 * n as f64).sum();
+
+    let mut dark_count = 0.0;
+    let mut dark_sum = 0.0;
+    let mut best = (0.0, 0);
+
+    for (v, &n) in histogram.iter().enumerate() {
+        dark_count += n as f64;
+        dark_sum += v as f64 * n as f64;
+
+        let light_count = total - dark_count;
+        if dark_count == 0.0 || light_count == 0.0 {
+            continue;
+        }
+
+        let dark_mean = dark_sum / dark_count;
+        let light_mean = (total_sum - dark_sum) / light_count;
+        let between = dark_count * light_count * (dark_mean - light_mean).powi(2);
+
+        if between > best.0 {
+            best = (between, v as u8);
+        }
+    }
+
+    best.1
+}
+
+/// Marks the pixels for which `is_dark(level, mean, standard deviation)` holds, with the mean and deviation taken over
+/// the window around each pixel. `window` is the side of the window as a fraction of the image's shorter side.
+fn local_threshold(levels: &[u8], width: usize, height: usize, window: f64, is_dark: impl Fn(f64, f64, f64) -> bool + Sync) -> Vec<bool> {
+    // summed area tables, with an extra row and column of zeros at the top and left
+    let stride = width + 1;
+    let mut sums = vec![0u64; stride*(height+1)];
+    let mut squares = vec![0u64; stride*(height+1)];
+
+    for y in 0..height {
+        let (mut row_sum, mut row_squares) = (0, 0);
+        for x in 0..width {
+            let v = levels[y*width + x] as u64;
+            row_sum += v;
+            row_squares += v*v;
+            sums[(y+1)*stride + x+1] = sums[y*stride + x+1] + row_sum;
+            squares[(y+1)*stride + x+1] = squares[y*stride + x+1] + row_squares;
+        }
+    }
+
+    let radius = ((window * width.min(height) as f64 / 2.0).round() as usize).max(1);
+    let area = |table: &[u64], x0: usize, y0: usize, x1: usize, y1: usize| {
+        table[y1*stride + x1] + table[y0*stride + x0] - table[y0*stride + x1] - table[y1*stride + x0]
+    };
+
+    let mut data = vec![false; width*height];
+
+    data.par_chunks_mut(width.max(1)).enumerate().for_each(|(y, row)| {
+        let (y0, y1) = (y.saturating_sub(radius), (y + radius + 1).min(height));
+
+        for (x, dark) in row.iter_mut().enumerate() {
+            let (x0, x1) = (x.saturating_sub(radius), (x + radius + 1).min(width));
+
+            let count = ((x1 - x0) * (y1 - y0)) as f64;
+            let mean = area(&sums, x0, y0, x1, y1) as f64 / count;
+            let variance = area(&squares, x0, y0, x1, y1) as f64 / count - mean*mean;
+
+            *dark = is_dark(levels[y*width + x] as f64, mean, variance.max(0.0).sqrt());
+        }
+    });
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::image::Color;
+    use crate::parse::scan_options::TemplateDropout;
+
+    /// Levels that jump around, so every window sees something different
+    fn noisy_levels(width: usize, height: usize) -> Vec<u8> {
+        (0..width*height).map(|i| ((i % width)*37 + (i / width)*91 + (i % width)*(i / width)*13) as u8).collect()
+    }
+
+    /// The mean and standard deviation of the window around every pixel, added up pixel by pixel
+    fn brute_force(levels: &[u8], width: usize, height: usize, radius: usize) -> Vec<(f64, f64)> {
+        (0..width*height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                let window: Vec<f64> = (y.saturating_sub(radius)..(y + radius + 1).min(height))
+                    .flat_map(|y| (x.saturating_sub(radius)..(x + radius + 1).min(width)).map(move |x| (x, y)))
+                    .map(|(x, y)| levels[y*width + x] as f64)
+                    .collect();
+
+                let mean = window.iter().sum::<f64>() / window.len() as f64;
+                let variance = window.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / window.len() as f64;
+                (mean, variance.sqrt())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn otsu_splits_two_clusters() {
+        let mut levels = vec![30; 100];
+        levels.extend(vec![200; 300]);
+        assert_eq!(otsu_level(&levels), 30);
+
+        // a few stray levels in between don't pull it far
+        levels.extend([90, 100, 110, 120]);
+        let level = otsu_level(&levels);
+        assert!((30..120).contains(&level), "{}", level);
+    }
+
+    #[test]
+    fn otsu_with_one_level_keeps_nothing_apart() {
+        assert_eq!(otsu_level(&[128; 50]), 0);
+    }
+
+    #[test]
+    fn summed_area_tables_match_brute_force() {
+        let (width, height) = (23, 17);
+        let levels = noisy_levels(width, height);
+        let window = 0.3; // a radius of 3 pixels, clipped at the edges
+
+        let expected = brute_force(&levels, width, height, 3);
+
+        for offset in [-20.0, 0.0, 15.0] {
+            let dark = local_threshold(&levels, width, height, window, |v, mean, _| v < mean - offset);
+            let brute: Vec<bool> = levels.iter().zip(expected.iter()).map(|(&v, &(mean, _))| (v as f64) < mean - offset).collect();
+            assert_eq!(dark, brute);
+        }
+
+        for spread in [40.0, 70.0, 90.0] {
+            let dark = local_threshold(&levels, width, height, window, |_, _, deviation| deviation > spread);
+            let brute: Vec<bool> = expected.iter().map(|&(_, deviation)| deviation > spread).collect();
+            assert_eq!(dark, brute);
+        }
+    }
+
+    #[test]
+    fn local_thresholds_follow_uneven_lighting() {
+        // paper going from dim on the left to bright on the right, with a mark in the dim part and one in the bright part
+        let (width, height) = (60, 20);
+        let image = Image::from_fn(width, height, |x, y| {
+            let paper = 100 + 2*x as u8;
+            let level = if (8..12).contains(&x) && (8..12).contains(&y) || (48..52).contains(&x) && (8..12).contains(&y) { paper - 60 } else { paper };
+            Color::from_rgb(level, level, level)
+        });
+        let options = |threshold| ScanOptions { threshold, template_dropout: None, ..ScanOptions::default() };
+
+        let fixed = BooleanMatrix::from_image(&image, &options(Threshold::Fixed(110)));
+        assert!(fixed.is_set(2, 2), "dim paper reads as dark");
+        assert!(!fixed.is_set(50, 10), "the mark on bright paper is missed");
+
+        for threshold in [Threshold::MeanOfWindow { window: 0.5, offset: 20.0 }, Threshold::Sauvola { window: 0.5, k: 0.2 }] {
+            let dark = BooleanMatrix::from_image(&image, &options(threshold));
+            assert!(dark.is_set(10, 10) && dark.is_set(50, 10), "{:?} finds both marks", threshold);
+            assert!(!dark.is_set(2, 2) && !dark.is_set(30, 2) && !dark.is_set(57, 17), "{:?} keeps the paper light", threshold);
+        }
+    }
+
+    #[test]
+    fn dropout_keeps_the_template_out_of_adaptive_thresholds() {
+        // a dim photo: a pencil mark next to a line of template ink, which is darker than the paper
+        let (width, height) = (60, 20);
+        let image = Image::from_fn(width, height, |x, y| match x {
+            _ if (4..8).contains(&x) && (8..12).contains(&y) => Color::from_rgb(30, 30, 30),
+            20..=25 => Color::from_rgb(0x7C, 0x88, 0x92),
+            _ => Color::from_rgb(230, 230, 230),
+        });
+        let options = |threshold, template_dropout| ScanOptions { threshold, template_dropout, ..ScanOptions::default() };
+
+        for threshold in [Threshold::Otsu, Threshold::Sauvola { window: 0.5, k: 0.2 }] {
+            let kept = BooleanMatrix::from_image(&image, &options(threshold, None));
+            assert!(kept.is_set(22, 10), "{:?} reads the template as dark", threshold);
+
+            let dropped = BooleanMatrix::from_image(&image, &options(threshold, Some(TemplateDropout::default())));
+            assert!(!dropped.is_set(22, 10) && !dropped.is_set(25, 2), "{:?} reads the template as paper", threshold);
+            assert!(dropped.is_set(5, 10), "{:?} still finds the mark", threshold);
+        }
+    }
+}
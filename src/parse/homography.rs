@@ -0,0 +1,439 @@
+use rulinalg::matrix::Matrix;
+use rulinalg::vector::Vector;
+
+// https://www.pyimagesearch.com/2014/08/25/4-point-opencv-getperspective-transform-example/
+// https://docs.opencv.org/2.4/modules/imgproc/doc/geometric_transformations.html?highlight=getperspectivetransform#void%20warpPerspective(InputArray%20src,%20OutputArray%20dst,%20InputArray%20M,%20Size%20dsize,%20int%20flags,%20int%20borderMode,%20const%20Scalar&%20borderValue)
+// https://github.com/opencv/opencv/blob/11b020b9f9e111bddd40bffe3b1759aa02d966f0/modules/imgproc/src/imgwarp.cpp
+
+/// A perspective transformation of the plane, like the one taking a flat page to a photo of it.
+/// It keeps its inverse alongside it, so points can be mapped either way.
+#[derive(Clone, Copy, Debug)]
+pub struct Homography {
+    forward: [[f64; 3]; 3],
+    backward: [[f64; 3]; 3],
+}
+
+impl Homography {
+    /// Leaves every point where it is
+    pub fn identity() -> Homography {
+        let identity = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+        Homography { forward: identity, backward: identity }
+    }
+
+    /// Stretches the plane by `sx` horizontally and `sy` vertically. Returns None if either is zero.
+    pub fn scaling(sx: f64, sy: f64) -> Option<Homography> {
+        Homography::from_matrix([[sx, 0.0, 0.0], [0.0, sy, 0.0], [0.0, 0.0, 1.0]])
+    }
+
+    /// Uses `matrix` on points in homogeneous coordinates, `(x, y, 1)`. Returns None if it can't be inverted.
+    pub fn from_matrix(matrix: [[f64; 3]; 3]) -> Option<Homography> {
+        if !matrix.concat().iter().all(|n| n.is_finite()) {
+            return None;
+        }
+
+        let backward = invert(&matrix)?;
+        Some(Homography { forward: matrix, backward })
+    }
+
+    /// The transformation taking each of four points in `from` to the matching point in `to`.
+    /// Returns None if there aren't four of each, or if the points don't determine a transformation, for example if
+    /// three of them are in a line.
+    pub fn from_correspondences(from: &[(f64, f64)], to: &[(f64, f64)]) -> Option<Homography> {
+        if from.len() != 4 || to.len() != 4 {
+            return None;
+        }
+
+        let mut a = Matrix::zeros(8, 8);
+        let mut b = Vector::zeros(8);
+        for i in 0..4 {
+            let t = from[i].0;
+            a[[i+4,3]] = t;
+            a[[i,0]] = t;
+
+            let t = from[i].1;
+            a[[i+4,4]] = t;
+            a[[i,1]] = t;
+
+            a[[i+4,5]] = 1.0;
+            a[[i,2]] = 1.0;
+
+            a[[i,6]] = -from[i].0*to[i].0;
+            a[[i,7]] = -from[i].1*to[i].0;
+            a[[i+4,6]] = -from[i].0*to[i].1;
+            a[[i+4,7]] = -from[i].1*to[i].1;
+
+            b[i] = to[i].0;
+            b[i+4] = to[i].1;
+        }
+
+        // calculate the transformation matrix m such that a*m = b
+        let m = a.solve(b).ok()?.into_vec();
+
+        Homography::from_matrix([[m[0], m[1], m[2]], [m[3], m[4], m[5]], [m[6], m[7], 1.0]])
+    }
+
+    /// The transformation taking each of three points in `from` to the matching point in `to` without any perspective,
+    /// so that parallel lines stay parallel. Returns None if there aren't three of each, or if the points are in a line.
+    pub fn affine_from_correspondences(from: &[(f64, f64)], to: &[(f64, f64)]) -> Option<Homography> {
+        if from.len() != 3 || to.len() != 3 {
+            return None;
+        }
+
+        let a = Matrix::new(3, 3, from.iter().flat_map(|&(x, y)| [x, y, 1.0]).collect::<Vec<_>>());
+
+        let x_row = a.clone().solve(Vector::new(to.iter().map(|p| p.0).collect::<Vec<_>>())).ok()?.into_vec();
+        let y_row = a.solve(Vector::new(to.iter().map(|p| p.1).collect::<Vec<_>>())).ok()?.into_vec();
+
+        Homography::from_matrix([[x_row[0], x_row[1], x_row[2]], [y_row[0], y_row[1], y_row[2]], [0.0, 0.0, 1.0]])
+    }
+
+    /// The transformation that best takes each point in `from` to the matching point in `to`, in the least squares
+    /// sense. Needs at least four pairs of points, and returns None if there aren't, if `from` and `to` aren't the
+    /// same length, or if the points don't determine a transformation.
+    pub fn fit(from: &[(f64, f64)], to: &[(f64, f64)]) -> Option<Homography> {
+        if from.len() != to.len() || from.len() < 4 {
+            return None;
+        }
+
+        // solving with the points centered and scaled keeps the normal equations well conditioned, even for photos
+        // thousands of pixels across. This is Hartley's normalization
+        let from_normalization = normalization(from)?;
+        let to_normalization = normalization(to)?;
+
+        let mut normal = Matrix::zeros(8, 8);
+        let mut right = Vector::zeros(8);
+
+        for (&from, &to) in from.iter().zip(to.iter()) {
+            let (x, y) = from_normalization.map(from);
+            let (u, v) = to_normalization.map(to);
+
+            // the two rows of the system `from_correspondences` solves, contributed by this pair of points
+            let rows = [
+                ([x, y, 1.0, 0.0, 0.0, 0.0, -x*u, -y*u], u),
+                ([0.0, 0.0, 0.0, x, y, 1.0, -x*v, -y*v], v),
+            ];
+
+            for (row, target) in rows.iter() {
+                for i in 0..8 {
+                    for j in 0..8 {
+                        normal[[i,j]] += row[i]*row[j];
+                    }
+                    right[i] += row[i]*target;
+                }
+            }
+        }
+
+        let m = normal.solve(right).ok()?.into_vec();
+        let normalized = Homography::from_matrix([[m[0], m[1], m[2]], [m[3], m[4], m[5]], [m[6], m[7], 1.0]])?;
+
+        Some(from_normalization.then(&normalized).then(&to_normalization.inverse()))
+    }
+
+    /// Like `fit`, but leaves out the pairs of points that don't agree with the rest. Every four of the pairs are
+    /// tried, which is quick for the handful of points a page has; the transformation that the most pairs land within
+    /// `tolerance` of is then refit to just those pairs. Distances are measured in the plane of `from`.
+    /// Returns the transformation, and whether each pair was kept, or None if `from` and `to` aren't the same length.
+    pub fn fit_robust(from: &[(f64, f64)], to: &[(f64, f64)], tolerance: f64) -> Option<(Homography, Vec<bool>)> {
+        if from.len() != to.len() {
+            return None;
+        }
+
+        let mut best: Option<(Vec<bool>, f64)> = None; // inliers, and their reprojection error
+
+        for sample in combinations(from.len(), 4) {
+            let sample_from: Vec<_> = sample.iter().map(|&i| from[i]).collect();
+            let sample_to: Vec<_> = sample.iter().map(|&i| to[i]).collect();
+
+            let candidate = match Homography::from_correspondences(&sample_from, &sample_to) {
+                Some(candidate) => candidate,
+                None => continue, // three of the points are in a line
+            };
+
+            let errors: Vec<f64> = from.iter().zip(to.iter()).map(|(&f, &t)| candidate.error(f, t)).collect();
+            let inliers: Vec<bool> = errors.iter().map(|&e| e <= tolerance).collect();
+            let count = inliers.iter().filter(|&&inlier| inlier).count();
+            let error = root_mean_square(errors.iter().zip(inliers.iter()).filter(|(_, &inlier)| inlier).map(|(&e, _)| e));
+
+            let better = match best {
+                Some((ref best_inliers, best_error)) => {
+                    let best_count = best_inliers.iter().filter(|&&inlier| inlier).count();
+                    count > best_count || (count == best_count && error < best_error)
+                },
+                None => true,
+            };
+
+            if better {
+                best = Some((inliers, error));
+            }
+        }
+
+        let (inliers, _) = best?;
+
+        let homography = Homography::fit(&kept(from, &inliers), &kept(to, &inliers))?;
+
+        Some((homography, inliers))
+    }
+
+    /// The root mean square distance, in the plane of `from`, between each point of `from` and where its partner in
+    /// `to` maps back to
+    pub fn reprojection_error(&self, from: &[(f64, f64)], to: &[(f64, f64)]) -> f64 {
+        root_mean_square(from.iter().zip(to.iter()).map(|(&f, &t)| self.error(f, t)))
+    }
+
+    fn error(&self, from: (f64, f64), to: (f64, f64)) -> f64 {
+        let (x, y) = self.map_inverse(to);
+        let error = (x - from.0).hypot(y - from.1);
+
+        if error.is_nan() { f64::INFINITY } else { error } // mapped to infinity
+    }
+
+    /// Where `point` ends up. Points sent to infinity come back with infinite or NaN coordinates.
+    pub fn map(&self, point: (f64, f64)) -> (f64, f64) {
+        apply(&self.forward, point)
+    }
+
+    /// Where `point` came from, so that `map(map_inverse(p))` is `p`
+    pub fn map_inverse(&self, point: (f64, f64)) -> (f64, f64) {
+        apply(&self.backward, point)
+    }
+
+    /// The transformation that undoes this one
+    pub fn inverse(&self) -> Homography {
+        Homography { forward: self.backward, backward: self.forward }
+    }
+
+    /// The transformation that applies this one, then `next`
+    pub fn then(&self, next: &Homography) -> Homography {
+        Homography {
+            forward: multiply(&next.forward, &self.forward),
+            backward: multiply(&self.backward, &next.backward),
+        }
+    }
+
+    /// The matrix used on points in homogeneous coordinates, `(x, y, 1)`
+    pub fn matrix(&self) -> [[f64; 3]; 3] {
+        self.forward
+    }
+}
+
+fn apply(m: &[[f64; 3]; 3], (x, y): (f64, f64)) -> (f64, f64) {
+    let w = m[2][0]*x + m[2][1]*y + m[2][2];
+
+    ((m[0][0]*x + m[0][1]*y + m[0][2]) / w, (m[1][0]*x + m[1][1]*y + m[1][2]) / w)
+}
+
+fn multiply(a: &[[f64; 3]; 3], b: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let mut product = [[0.0; 3]; 3];
+
+    for (i, row) in product.iter_mut().enumerate() {
+        for (j, cell) in row.iter_mut().enumerate() {
+            *cell = (0..3).map(|k| a[i][k]*b[k][j]).sum();
+        }
+    }
+
+    product
+}
+
+/// Returns None if `m` is singular, or so close to it that the inverse isn't finite
+fn invert(m: &[[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let inverse = Matrix::new(3, 3, m.concat()).inverse().ok()?;
+
+    // solve and inverse can quietly succeed on a nearly singular matrix, giving us garbage
+    if !inverse.data().iter().all(|n| n.is_finite()) {
+        return None;
+    }
+
+    let d = inverse.data();
+    Some([[d[0], d[1], d[2]], [d[3], d[4], d[5]], [d[6], d[7], d[8]]])
+}
+
+/// Moves the centroid of `points` to the origin and scales them to an average distance of the square root of two from
+/// it. Returns None if the points are all the same.
+fn normalization(points: &[(f64, f64)]) -> Option<Homography> {
+    let n = points.len() as f64;
+    let (cx, cy) = points.iter().fold((0.0, 0.0), |(sx, sy), &(x, y)| (sx + x/n, sy + y/n));
+    let mean_distance = points.iter().map(|&(x, y)| (x - cx).hypot(y - cy)).sum::<f64>() / n;
+
+    let scale = std::f64::consts::SQRT_2 / mean_distance;
+
+    Homography::from_matrix([[scale, 0.0, -scale*cx], [0.0, scale, -scale*cy], [0.0, 0.0, 1.0]])
+}
+
+fn root_mean_square(values: impl Iterator<Item=f64>) -> f64 {
+    let (sum, count) = values.fold((0.0, 0), |(sum, count), v| (sum + v*v, count + 1));
+
+    if count == 0 { 0.0 } else { (sum / count as f64).sqrt() }
+}
+
+/// The items of `items` that `keep` is true for
+pub(crate) fn kept<T: Copy>(items: &[T], keep: &[bool]) -> Vec<T> {
+    items.iter().zip(keep.iter()).filter(|(_, &keep)| keep).map(|(&item, _)| item).collect()
+}
+
+/// Every way of choosing `k` of the indices below `n`, each in increasing order
+pub(crate) fn combinations(n: usize, k: usize) -> impl Iterator<Item=Vec<usize>> {
+    let mut next = if k <= n { Some((0..k).collect::<Vec<_>>()) } else { None };
+
+    std::iter::from_fn(move || {
+        let current = next.take()?;
+
+        // find the last index that can still move right, move it, and pack the ones after it behind it
+        let mut advanced = current.clone();
+        if let Some(i) = (0..k).rev().find(|&i| advanced[i] < n - k + i) {
+            advanced[i] += 1;
+            for j in i+1..k {
+                advanced[j] = advanced[j-1] + 1;
+            }
+            next = Some(advanced);
+        }
+
+        Some(current)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SQUARE: [(f64, f64); 4] = [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+    const PHOTO: [(f64, f64); 4] = [(120.0, 80.0), (910.0, 130.0), (870.0, 1040.0), (60.0, 990.0)];
+
+    fn assert_close(a: (f64, f64), b: (f64, f64)) {
+        assert!((a.0 - b.0).abs() < 1e-6 && (a.1 - b.1).abs() < 1e-6, "{:?} is not {:?}", a, b);
+    }
+
+    #[test]
+    fn correspondences_map_exactly() {
+        let page_to_photo = Homography::from_correspondences(&SQUARE, &PHOTO).unwrap();
+
+        for (&page, &photo) in SQUARE.iter().zip(PHOTO.iter()) {
+            assert_close(page_to_photo.map(page), photo);
+            assert_close(page_to_photo.map_inverse(photo), page);
+        }
+
+        // the middle of the page is where the diagonals of the photo cross, not the average of the corners
+        let middle = page_to_photo.map((0.5, 0.5));
+        let cross = |(x0, y0): (f64, f64), (x1, y1): (f64, f64), p: (f64, f64)| (x1 - x0)*(p.1 - y0) - (y1 - y0)*(p.0 - x0);
+        assert!(cross(PHOTO[0], PHOTO[2], middle).abs() < 1e-6 && cross(PHOTO[1], PHOTO[3], middle).abs() < 1e-6);
+    }
+
+    #[test]
+    fn composition_and_inverse() {
+        let page_to_photo = Homography::from_correspondences(&SQUARE, &PHOTO).unwrap();
+        let scaling = Homography::scaling(500.0, 250.0).unwrap();
+        let both = scaling.inverse().then(&page_to_photo);
+
+        assert_close(both.map((250.0, 125.0)), page_to_photo.map((0.5, 0.5)));
+        assert_close(both.inverse().map(PHOTO[2]), (500.0, 250.0));
+        assert_close(page_to_photo.then(&page_to_photo.inverse()).map((0.3, 0.7)), (0.3, 0.7));
+    }
+
+    #[test]
+    fn degenerate_correspondences() {
+        let in_a_line = [(0.0, 0.0), (1.0, 1.0), (2.0, 2.0), (0.0, 1.0)];
+        assert!(Homography::from_correspondences(&in_a_line, &PHOTO).is_none());
+        assert!(Homography::scaling(0.0, 1.0).is_none());
+        assert!(Homography::from_matrix([[1.0, 0.0, f64::NAN], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]).is_none());
+    }
+
+    /// Points spread over the page, where fiducials might be
+    fn page_points() -> Vec<(f64, f64)> {
+        vec![(0.05, 0.05), (0.95, 0.05), (0.95, 0.95), (0.05, 0.95), (0.05, 0.5), (0.95, 0.5), (0.5, 0.95), (0.4, 0.3)]
+    }
+
+    #[test]
+    fn fit_recovers_a_transformation_from_many_points() {
+        let truth = Homography::from_correspondences(&SQUARE, &PHOTO).unwrap();
+        let from = page_points();
+        let to: Vec<_> = from.iter().map(|&p| truth.map(p)).collect();
+
+        let fit = Homography::fit(&from, &to).unwrap();
+        for &p in from.iter().chain([(0.5, 0.5), (0.2, 0.8)].iter()) {
+            assert_close(fit.map(p), truth.map(p));
+        }
+        assert!(fit.reprojection_error(&from, &to) < 1e-9);
+    }
+
+    #[test]
+    fn fit_spreads_out_noise() {
+        let truth = Homography::from_correspondences(&SQUARE, &PHOTO).unwrap();
+        let from = page_points();
+        // half a pixel off, one way or the other
+        let to: Vec<_> = from.iter().enumerate()
+            .map(|(i, &p)| (truth.map(p), if i % 2 == 0 { 0.5 } else { -0.5 }))
+            .map(|((x, y), off)| (x + off, y - off))
+            .collect();
+
+        let fit = Homography::fit(&from, &to).unwrap();
+        let (x, y) = fit.map((0.5, 0.5));
+        let (true_x, true_y) = truth.map((0.5, 0.5));
+        assert!((x - true_x).hypot(y - true_y) < 1.0);
+    }
+
+    #[test]
+    fn fit_robust_leaves_out_a_planted_outlier() {
+        let truth = Homography::from_correspondences(&SQUARE, &PHOTO).unwrap();
+        let from = page_points();
+        let mut to: Vec<_> = from.iter().map(|&p| truth.map(p)).collect();
+        to[5].0 += 40.0; // an aligner found somewhere it isn't, about 0.05 of the page off
+
+        let (fit, inliers) = Homography::fit_robust(&from, &to, 0.01).unwrap();
+        assert_eq!(inliers, [true, true, true, true, true, false, true, true]);
+        assert_close(fit.map((0.5, 0.5)), truth.map((0.5, 0.5)));
+        assert!(fit.reprojection_error(&kept(&from, &inliers), &kept(&to, &inliers)) < 1e-9);
+
+        // a plain fit is pulled towards it
+        let plain = Homography::fit(&from, &to).unwrap();
+        assert!(plain.reprojection_error(&from, &to) > 0.001);
+    }
+
+    #[test]
+    fn fit_robust_with_four_points_keeps_them_all() {
+        // four points always agree, so nothing can be left out
+        let mut to = PHOTO;
+        to[2].0 += 200.0;
+
+        let (fit, inliers) = Homography::fit_robust(&SQUARE, &to, 0.01).unwrap();
+        assert_eq!(inliers, [true; 4]);
+        assert!(fit.reprojection_error(&SQUARE, &to) < 1e-9);
+    }
+
+    #[test]
+    fn combinations_in_order() {
+        let all: Vec<Vec<usize>> = combinations(4, 2).collect();
+        assert_eq!(all, [vec![0, 1], vec![0, 2], vec![0, 3], vec![1, 2], vec![1, 3], vec![2, 3]]);
+
+        assert_eq!(combinations(7, 4).count(), 35);
+        assert_eq!(combinations(3, 3).collect::<Vec<_>>(), [vec![0, 1, 2]]);
+        assert_eq!(combinations(5, 0).collect::<Vec<_>>(), [Vec::<usize>::new()]);
+        assert_eq!(combinations(3, 4).count(), 0);
+        assert_eq!(combinations(0, 1).count(), 0);
+    }
+
+    #[test]
+    fn affine_correspondences_keep_parallel_lines_parallel() {
+        let from = [(0.05, 0.05), (0.95, 0.05), (0.05, 0.95)];
+        let to = [(100.0, 50.0), (900.0, 150.0), (0.0, 850.0)];
+        let affine = Homography::affine_from_correspondences(&from, &to).unwrap();
+
+        for (&from, &to) in from.iter().zip(to.iter()) {
+            assert_close(affine.map(from), to);
+        }
+        // the fourth corner completes the parallelogram
+        assert_close(affine.map((0.95, 0.95)), (800.0, 950.0));
+        assert_eq!(affine.matrix()[2], [0.0, 0.0, 1.0]);
+
+        assert!(Homography::affine_from_correspondences(&[(0.0, 0.0), (0.5, 0.5), (1.0, 1.0)], &to).is_none());
+    }
+
+    #[test]
+    fn wrong_numbers_of_points_are_not_a_panic() {
+        assert!(Homography::from_correspondences(&SQUARE[..3], &PHOTO[..3]).is_none());
+        assert!(Homography::from_correspondences(&SQUARE, &PHOTO[..3]).is_none());
+        assert!(Homography::affine_from_correspondences(&SQUARE, &PHOTO).is_none());
+        assert!(Homography::affine_from_correspondences(&SQUARE[..3], &PHOTO[..2]).is_none());
+        assert!(Homography::fit(&SQUARE, &PHOTO[..3]).is_none());
+        assert!(Homography::fit(&SQUARE[..3], &PHOTO[..3]).is_none());
+        assert!(Homography::fit_robust(&SQUARE, &PHOTO[..3], 0.01).is_none());
+    }
+}
@@ -1,11 +1,18 @@
-use png::{Decoder, Encoder, ColorType, BitDepth};
-use png::HasParameters;
+use png::{Decoder, Encoder, ColorType, BitDepth, Transformations};
+use jpeg_decoder::PixelFormat;
+use tiff::decoder::DecodingResult;
 
+use std::fmt;
 use std::fs::File;
-use std::io::BufWriter;
-use rulinalg::matrix::Matrix;
-use rulinalg::vector::Vector;
+use std::io::{self, BufRead, BufReader, BufWriter, Read};
+use std::path::Path;
+use rayon::prelude::*;
 
+use crate::parse::homography::Homography;
+use crate::parse::scan_error::ScanError;
+use crate::parse::scan_options::Interpolation;
+
+/// An 8 bit RGB color.
 #[derive(Clone, Copy)]
 pub struct Color {
     r: u8,
@@ -14,94 +21,276 @@ pub struct Color {
 }
 
 impl Color {
+    /// Makes a color from its red, green, and blue channels
     pub const fn from_rgb(r: u8, g: u8, b: u8) -> Color {
         Color { r, g, b }
     }
 
-    pub const fn red() -> Color {
+    pub(crate) const fn red() -> Color {
         Color::from_rgb(0xff, 0, 0)
     }
 
-    pub const fn green() -> Color {
+    pub(crate) const fn green() -> Color {
         Color::from_rgb(0, 0xff, 0)
     }
 
-    pub const fn blue() -> Color {
+    pub(crate) const fn blue() -> Color {
         Color::from_rgb(0, 0, 0xff)
     }
 
-    pub const fn magenta() -> Color {
+    pub(crate) const fn magenta() -> Color {
         Color::from_rgb(0xff, 0, 0xff)
     }
 
-    pub const fn yellow() -> Color {
+    pub(crate) const fn yellow() -> Color {
         Color::from_rgb(0xff, 0xff, 0)
     }
 
-    pub const fn cyan() -> Color {
+    pub(crate) const fn cyan() -> Color {
         Color::from_rgb(0, 0xff, 0xff)
     }
 
-    pub fn is_darker_than(self, threshold: u8) -> bool {
-        self.r < threshold && self.g < threshold && self.b < threshold
+    /// The red, green, and blue channels
+    pub(crate) fn rgb(self) -> [u8; 3] {
+        [self.r, self.g, self.b]
+    }
+
+    /// The brightest channel, so that a color is only as dark as it is in every channel
+    pub(crate) fn brightness(self) -> u8 {
+        self.r.max(self.g).max(self.b)
+    }
+
+    /// The hue in degrees from 0 to 360, with red at 0, and the saturation from 0 to 1, as in HSV.
+    /// Grays have a hue of 0.
+    pub(crate) fn hue_saturation(self) -> (f64, f64) {
+        let (r, g, b) = (self.r as f64, self.g as f64, self.b as f64);
+        let max = r.max(g).max(b);
+        let chroma = max - r.min(g).min(b);
+
+        if chroma == 0.0 {
+            return (0.0, 0.0);
+        }
+
+        let sector = if max == r {
+            ((g - b) / chroma).rem_euclid(6.0)
+        } else if max == g {
+            (b - r) / chroma + 2.0
+        } else {
+            (r - g) / chroma + 4.0
+        };
+
+        (60.0 * sector, chroma / max)
+    }
+
+    /// How light the color looks, weighting the channels like ITU-R BT.601
+    pub(crate) fn gray(self) -> u8 {
+        ((299*self.r as u32 + 587*self.g as u32 + 114*self.b as u32 + 500) / 1000) as u8
     }
 }
 
+/// Formats the color like `#CFE2F3`, as used in SVG and CSS
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:02X}{:02X}{:02X}", self.r, self.g, self.b)
+    }
+}
+
+/// An 8 bit RGB image, stored row by row.
 #[derive(Clone)]
 pub struct Image {
-    data: Vec<u8>,
-    pub base: usize,
-    pub height: usize,
+    pub(crate) data: Vec<u8>,
+    pub(crate) base: usize,
+    pub(crate) height: usize,
 }
 
 impl Image {
+    /// `data` holds the red, green, and blue channels of every pixel, row by row
     pub fn from_raw(data: Vec<u8>, base: usize, height: usize) -> Image {
         assert_eq!(data.len(), 3*base*height);
         Image { data, base, height }
     }
 
-    pub fn from_fn(base: usize, height: usize, f: impl Fn(usize, usize) -> Color) -> Image {
-        let mut data = Vec::with_capacity(base*height*3);
+    /// Makes an image by calling `f` with the x and y of every pixel. Rows are filled in parallel.
+    pub fn from_fn(base: usize, height: usize, f: impl Fn(usize, usize) -> Color + Sync) -> Image {
+        let mut data = vec![0; base*height*3];
 
-        for y in 0..height {
-            for x in 0..base {
+        data.par_chunks_mut(3*base.max(1)).enumerate().for_each(|(y, row)| {
+            for (x, pixel) in row.chunks_mut(3).enumerate() {
                 let color = f(x, y);
-                data.push(color.r);
-                data.push(color.g);
-                data.push(color.b);
+                pixel[0] = color.r;
+                pixel[1] = color.g;
+                pixel[2] = color.b;
             }
-        }
+        });
 
         Image { data, base, height }
     }
 
-    pub fn output_to_file(&self, name: &str) {
-        let output_file = BufWriter::new(File::create(name).unwrap());
+    /// Writes the image as a binary PPM if `name` ends in `.ppm` or `.pnm`, a binary PGM if it ends in `.pgm`,
+    /// and a PNG otherwise
+    pub fn output_to_file(&self, name: &Path) -> io::Result<()> {
+        let output_file = BufWriter::new(File::create(name)?);
+
+        match name.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase()).as_deref() {
+            Some("ppm") | Some("pnm") => return self.output_ppm(output_file),
+            Some("pgm") => return self.output_pgm(output_file),
+            _ => {},
+        }
 
         let mut encoder = Encoder::new(output_file, self.base as u32, self.height as u32);
-        encoder.set(png::BitDepth::Eight).set(png::ColorType::RGB);
+        encoder.set_depth(BitDepth::Eight);
+        encoder.set_color(ColorType::Rgb);
 
-        let mut writer = encoder.write_header().unwrap();
+        let mut writer = encoder.write_header()?;
 
-        writer.write_image_data(&self.data).unwrap();
+        writer.write_image_data(&self.data)?;
+
+        Ok(())
+    }
+
+    /// Reads a PNG, JPEG, TIFF, or Netpbm image, telling them apart by their contents rather than the file's extension.
+    /// PNGs can be of any color type and bit depth, and transparent pixels are composited onto white.
+    /// JPEGs are turned upright according to their EXIF orientation, as phone cameras often store them sideways.
+    /// Only the first page of a multi-page TIFF is read; use `read_pages_from_file` to get the rest.
+    pub fn read_from_file(name: &Path) -> Result<Image, ScanError> {
+        Image::read_pages_from_file(name)?
+            .next()
+            .expect("every image file has a first page")
+    }
+
+    /// Reads every page of an image file, one at a time. PNGs and JPEGs have a single page, while TIFFs from a
+    /// document feeder can have one for each sheet, often 1 bit CCITT fax compressed. Only Group 4 fax pages can be
+    /// decoded; Group 3 and Modified Huffman ones are reported as an unsupported pixel format.
+    pub fn read_pages_from_file(name: &Path) -> Result<ImagePages, ScanError> {
+        let mut file = BufReader::new(File::open(name)?);
+
+        let pages = match ImageFormat::from_signature(file.fill_buf()?) {
+            Some(ImageFormat::Png) => Pages::Single(Some(Image::read_png(file))),
+            Some(ImageFormat::Jpeg) => Pages::Single(Some(Image::read_jpeg(file))),
+            Some(ImageFormat::Netpbm) => Pages::Single(Some(Image::read_netpbm(file))),
+            Some(ImageFormat::Tiff) => Pages::Tiff { decoder: Some(Box::new(tiff::decoder::Decoder::new(file)?)), started: false },
+            None => return Err(ScanError::UnrecognizedFormat),
+        };
+
+        Ok(ImagePages { pages })
     }
 
-    pub fn read_from_file(name: &str) -> Image {
-        let file = File::open(name).unwrap();
+    fn read_png(input: impl Read) -> Result<Image, ScanError> {
+        let mut decoder = Decoder::new(input);
+        // palettes, low bit depths, and transparency chunks are expanded, and 16 bit samples are cut down to 8 bits
+        decoder.set_transformations(Transformations::normalize_to_color8());
+
+        let mut reader = decoder.read_info()?;
+        let mut samples = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut samples)?;
+
+        let channels = match info.color_type {
+            ColorType::Grayscale => 1,
+            ColorType::GrayscaleAlpha => 2,
+            ColorType::Rgb => 3,
+            ColorType::Rgba => 4,
+            ColorType::Indexed => return Err(ScanError::UnsupportedPixelFormat(String::from("indexed PNG that wasn't expanded"))),
+        };
 
-        let (info, mut reader) = Decoder::new(file).read_info().unwrap();
         let base = info.width as usize;
         let height = info.height as usize;
+        samples.truncate(info.buffer_size());
 
-        assert_eq!(info.color_type, ColorType::RGB);
-        assert_eq!(info.bit_depth, BitDepth::Eight);
+        Ok(Image { data: rgb_from_samples(&samples, channels), base, height })
+    }
 
-        let mut data = vec![0; info.buffer_size()];
-        reader.next_frame(&mut data).unwrap();
+    fn read_jpeg(input: impl Read) -> Result<Image, ScanError> {
+        let mut decoder = jpeg_decoder::Decoder::new(input);
+        let samples = decoder.decode()?;
+        let info = decoder.info().expect("the header has been read once the image is decoded");
 
-        Image { data, base, height }
+        let data = match info.pixel_format {
+            PixelFormat::L8 => rgb_from_samples(&samples, 1),
+            PixelFormat::L16 => rgb_from_samples(&gray8_from_gray16(&samples), 1),
+            PixelFormat::RGB24 => samples,
+            PixelFormat::CMYK32 => rgb_from_cmyk(&samples),
+        };
+
+        let image = Image { data, base: info.width as usize, height: info.height as usize };
+
+        // a missing or broken EXIF block just means the image is already upright
+        let orientation = decoder.exif_data()
+            .and_then(|exif| exif::Reader::new().read_raw(exif.to_vec()).ok())
+            .and_then(|exif| exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?.value.get_uint(0))
+            .unwrap_or(1);
+
+        Ok(image.oriented(orientation))
     }
 
+    fn read_tiff_page(decoder: &mut tiff::decoder::Decoder<BufReader<File>>) -> Result<Image, ScanError> {
+        use tiff::ColorType;
+        use tiff::tags::{Tag, CompressionMethod};
+
+        // the decoder only has a generic error for these, which doesn't say why a fax page couldn't be read
+        let compression = decoder.find_tag_unsigned(Tag::Compression).ok().flatten().and_then(CompressionMethod::from_u16);
+        if let Some(method @ (CompressionMethod::Huffman | CompressionMethod::Fax3)) = compression {
+            return Err(ScanError::UnsupportedPixelFormat(format!("{:?} compressed TIFF, as only Group 4 (Fax4) fax pages can be read", method)));
+        }
+
+        let (base, height) = decoder.dimensions()?;
+        let (base, height) = (base as usize, height as usize);
+        let color_type = decoder.colortype()?;
+        let unsupported = || ScanError::UnsupportedPixelFormat(format!("{:?} TIFF", color_type));
+
+        // white-is-zero pages, which is how fax compressed pages are usually stored, are already inverted by the decoder
+        let samples = match (decoder.read_image()?, color_type.bit_depth()) {
+            (DecodingResult::U8(samples), 8) => samples,
+            (DecodingResult::U8(samples), bits @ (1 | 2 | 4)) => gray8_from_packed(&samples, bits, base),
+            (DecodingResult::U16(samples), 16) => samples.into_iter().map(|s| (s >> 8) as u8).collect(),
+            _ => return Err(unsupported()),
+        };
+
+        let data = match color_type {
+            ColorType::Gray(_) => rgb_from_samples(&samples, 1),
+            ColorType::GrayA(_) | ColorType::Multiband { num_samples: 2, .. } => rgb_from_samples(&samples, 2),
+            ColorType::RGB(_) => rgb_from_samples(&samples, 3),
+            ColorType::RGBA(_) => rgb_from_samples(&samples, 4),
+            ColorType::CMYK(_) => rgb_from_cmyk(&samples),
+            _ => return Err(unsupported()),
+        };
+
+        if data.len() != 3*base*height {
+            return Err(unsupported()); // for example planar images, which only have their first plane decoded
+        }
+
+        let orientation = decoder.find_tag_unsigned(Tag::Orientation).ok().flatten().unwrap_or(1);
+
+        Ok(Image { data, base, height }.oriented(orientation))
+    }
+
+    /// Undoes the rotation or mirroring described by an EXIF or TIFF orientation tag, from 1 (already upright) to 8
+    fn oriented(self, orientation: u32) -> Image {
+        let (base, height) = (self.base, self.height);
+        let (b, h) = (base.saturating_sub(1), height.saturating_sub(1)); // the largest x and y
+
+        match orientation {
+            2 => Image::from_fn(base, height, |x, y| self.get_color(b - x, y)), // mirrored left to right
+            3 => Image::from_fn(base, height, |x, y| self.get_color(b - x, h - y)), // upside down
+            4 => Image::from_fn(base, height, |x, y| self.get_color(x, h - y)), // mirrored top to bottom
+            5 => Image::from_fn(height, base, |x, y| self.get_color(y, x)), // mirrored along the main diagonal
+            6 => Image::from_fn(height, base, |x, y| self.get_color(y, h - x)), // needs a quarter turn clockwise
+            7 => Image::from_fn(height, base, |x, y| self.get_color(b - y, h - x)), // mirrored along the other diagonal
+            8 => Image::from_fn(height, base, |x, y| self.get_color(b - y, x)), // needs a quarter turn counterclockwise
+            _ => self,
+        }
+    }
+
+    /// The width of the image in pixels
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// The height of the image in pixels
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Panics if (x, y) is outside the image
     pub fn set_color(&mut self, x: usize, y: usize, color: Color) {
         let index = 3*(y*self.base + x);
         self.data[index] = color.r;
@@ -109,12 +298,14 @@ impl Image {
         self.data[index+2] = color.b;
     }
 
+    /// Panics if (x, y) is outside the image
     pub fn get_color(&self, x: usize, y: usize) -> Color {
         let index = 3*(y*self.base + x);
 
         Color::from_rgb(self.data[index], self.data[index+1], self.data[index+2])
     }
 
+    /// Returns None if (x, y) is outside the image
     pub fn get_color_checked(&self, x: usize, y: usize) -> Option<Color> {
         if x < self.base && y < self.height {
             Some(self.get_color(x, y))
@@ -123,60 +314,412 @@ impl Image {
         }
     }
 
-    pub fn perspective_transform(&self, src: &[(f64, f64)], dst: &[(f64, f64)], new_base: usize, new_height: usize) -> Image {
-        // https://www.pyimagesearch.com/2014/08/25/4-point-opencv-getperspective-transform-example/
-        // https://docs.opencv.org/2.4/modules/imgproc/doc/geometric_transformations.html?highlight=getperspectivetransform#void%20warpPerspective(InputArray%20src,%20OutputArray%20dst,%20InputArray%20M,%20Size%20dsize,%20int%20flags,%20int%20borderMode,%20const%20Scalar&%20borderValue)
-        // https://github.com/opencv/opencv/blob/11b020b9f9e111bddd40bffe3b1759aa02d966f0/modules/imgproc/src/imgwarp.cpp
-
+    /// The color at a point between pixels, with pixel centers at whole numbers, or None if the point is off the image
+    pub(crate) fn sample(&self, x: f64, y: f64, interpolation: Interpolation) -> Option<Color> {
+        let (base, height) = (self.base as f64, self.height as f64);
+
+        match interpolation {
+            Interpolation::Nearest => {
+                // truncating rather than rounding, like the warp always has
+                if x < 0.0 || y < 0.0 || x >= base || y >= height {
+                    return None;
+                }
+                Some(self.get_color(x as usize, y as usize))
+            },
+            Interpolation::Bilinear | Interpolation::Bicubic => {
+                if x < -0.5 || y < -0.5 || x > base - 0.5 || y > height - 0.5 {
+                    return None;
+                }
+
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fx, fy) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as isize, y0 as isize);
+
+                // neighbors past the edge take the color of the edge
+                let pixel = |dx: isize, dy: isize| {
+                    let px = (x0 + dx).clamp(0, self.base as isize - 1) as usize;
+                    let py = (y0 + dy).clamp(0, self.height as isize - 1) as usize;
+                    self.get_color(px, py).rgb()
+                };
+
+                // weights of the pixels from one before (x0, y0) to two after
+                let (weights_x, weights_y) = match interpolation {
+                    Interpolation::Bilinear => (linear_weights(fx), linear_weights(fy)),
+                    _ => (cubic_weights(fx), cubic_weights(fy)),
+                };
+
+                let mut channels = [0.0; 3];
+                for (dy, wy) in (-1..=2).zip(weights_y) {
+                    for (dx, wx) in (-1..=2).zip(weights_x).filter(|&(_, wx)| wx*wy != 0.0) {
+                        for (channel, value) in channels.iter_mut().zip(pixel(dx, dy)) {
+                            *channel += wx * wy * value as f64;
+                        }
+                    }
+                }
+
+                let [r, g, b] = channels.map(|c| c.round().clamp(0.0, 255.0) as u8);
+                Some(Color::from_rgb(r, g, b))
+            },
+        }
+    }
 
-        let m = get_perspective_shift_matrix(src, dst);
+    /// Makes a `new_base` by `new_height` image, taking the color of each of its pixels from wherever `new_to_self`
+    /// maps it in this image. Pixels that land outside this image are magenta.
+    pub fn warp(&self, new_to_self: &Homography, new_base: usize, new_height: usize, interpolation: Interpolation) -> Image {
+        self.warp_by(|point| new_to_self.map(point), new_base, new_height, interpolation)
+    }
 
-        // actually produce a new image with our transformation
+    /// Same as `warp`, but with any mapping from the new image's pixels to this one's
+    pub(crate) fn warp_by(&self, new_to_self: impl Fn((f64, f64)) -> (f64, f64) + Sync, new_base: usize, new_height: usize, interpolation: Interpolation) -> Image {
         Image::from_fn(new_base, new_height, |x: usize, y: usize| {
-            let x = x as f64;
-            let y = y as f64;
-
-            let new_x = (m[[0,0]]*x + m[[0,1]]*y + m[[0,2]]) / (m[[2,0]]*x + m[[2,1]]*y + m[[2,2]]);
-            let new_y = (m[[1,0]]*x + m[[1,1]]*y + m[[1,2]]) / (m[[2,0]]*x + m[[2,1]]*y + m[[2,2]]);
+            let (x, y) = new_to_self((x as f64, y as f64));
 
-            self.get_color_checked(new_x as usize, new_y as usize)
+            self.sample(x, y, interpolation)
                 .unwrap_or(Color::magenta()) // our debug value
         })
     }
 }
 
+/// The weights of the four pixels around a point `t` of the way from the second to the third, of which only the
+/// middle two count
+fn linear_weights(t: f64) -> [f64; 4] {
+    [0.0, 1.0 - t, t, 0.0]
+}
+
+/// The Catmull-Rom weights of the four pixels around a point `t` of the way from the second to the third
+fn cubic_weights(t: f64) -> [f64; 4] {
+    let (t2, t3) = (t*t, t*t*t);
+    [
+        (-t3 + 2.0*t2 - t) / 2.0,
+        (3.0*t3 - 5.0*t2 + 2.0) / 2.0,
+        (-3.0*t3 + 4.0*t2 + t) / 2.0,
+        (t3 - t2) / 2.0,
+    ]
+}
 
+/// The pages of an image file, from `Image::read_pages_from_file`.
+/// They're decoded one at a time, so a long stack of scanned sheets doesn't have to fit in memory all at once.
+pub struct ImagePages {
+    pages: Pages,
+}
 
-fn get_perspective_shift_matrix(src: &[(f64, f64)], dst: &[(f64, f64)]) -> Matrix<f64> {
-    assert_eq!(src.len(), 4);
-    assert_eq!(dst.len(), 4);
+enum Pages {
+    Single(Option<Result<Image, ScanError>>),
+    Tiff {
+        decoder: Option<Box<tiff::decoder::Decoder<BufReader<File>>>>, // None once we can't find any more pages
+        started: bool, // whether the decoder's current page has already been read
+    },
+}
 
-    let mut a = Matrix::zeros(8, 8);
-    let mut b = Vector::zeros(8);
-    for i in 0..4 {
-        let t = src[i].0;
-        a[[i+4,3]] = t;
-        a[[i,0]] = t;
+impl Iterator for ImagePages {
+    type Item = Result<Image, ScanError>;
+
+    fn next(&mut self) -> Option<Result<Image, ScanError>> {
+        match self.pages {
+            Pages::Single(ref mut image) => image.take(),
+            Pages::Tiff { ref mut decoder, ref mut started } => {
+                let current = decoder.as_mut()?;
+
+                if *started {
+                    if !current.more_images() {
+                        *decoder = None;
+                        return None;
+                    }
+
+                    // a page that can't be decoded doesn't stop us reading the rest, but a broken list of pages does
+                    if let Err(e) = current.next_image() {
+                        *decoder = None;
+                        return Some(Err(e.into()));
+                    }
+                }
+
+                *started = true;
+                Some(Image::read_tiff_page(current))
+            },
+        }
+    }
+}
 
-        let t = src[i].1;
-        a[[i+4,4]] = t;
-        a[[i,1]] = t;
+/// The kinds of file `Image::read_from_file` understands.
+enum ImageFormat {
+    Png,
+    Jpeg,
+    Tiff,
+    Netpbm,
+}
 
-        a[[i+4,5]] = 1.0;
-        a[[i,2]] = 1.0;
+impl ImageFormat {
+    /// Recognizes a format from the first few bytes of a file
+    fn from_signature(bytes: &[u8]) -> Option<ImageFormat> {
+        if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageFormat::Png)
+        } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+            Some(ImageFormat::Jpeg)
+        } else if [b"II*\0", b"MM\0*", b"II+\0", b"MM\0+"].iter().any(|signature| bytes.starts_with(*signature)) {
+            Some(ImageFormat::Tiff) // little and big endian, in both the classic and BigTIFF flavors
+        } else if let [b'P', b'1'..=b'6', whitespace, ..] = *bytes {
+            if whitespace.is_ascii_whitespace() { Some(ImageFormat::Netpbm) } else { None }
+        } else {
+            None
+        }
+    }
+}
+
+/// Cuts native endian 16 bit gray samples down to 8 bits. Lossless JPEGs can have anywhere from 2 to 16 bits of
+/// precision and don't tell us which, so the samples are scaled by the smallest bit depth that holds all of them.
+fn gray8_from_gray16(samples: &[u8]) -> Vec<u8> {
+    let samples: Vec<u16> = samples.chunks_exact(2).map(|s| u16::from_ne_bytes([s[0], s[1]])).collect();
+    let bits = 16 - samples.iter().max().unwrap_or(&0).leading_zeros();
+    let shift = bits.saturating_sub(8);
 
-        a[[i,6]] = -src[i].0*dst[i].0;
-        a[[i,7]] = -src[i].1*dst[i].0;
-        a[[i+4,6]] = -src[i].0*dst[i].1;
-        a[[i+4,7]] = -src[i].1*dst[i].1;
+    samples.into_iter().map(|s| (s >> shift) as u8).collect()
+}
+
+/// Spreads rows of 1, 2, or 4 bit gray samples, packed into bytes with each row starting on a new byte, out to 8 bits
+fn gray8_from_packed(packed: &[u8], bits: u8, base: usize) -> Vec<u8> {
+    let per_byte = 8 / bits as usize;
+    let row_bytes = base.div_ceil(per_byte);
+    let max = (1u16 << bits) - 1;
 
-        b[i] = dst[i].0;
-        b[i+4] = dst[i].1;
+    let mut samples = Vec::with_capacity(packed.len() * per_byte);
+
+    for row in packed.chunks(row_bytes.max(1)) {
+        for x in 0..base {
+            let byte = row.get(x / per_byte).copied().unwrap_or(0);
+            let shift = 8 - bits as usize * (x % per_byte + 1); // the first sample is in the high bits
+            let sample = (byte as u16 >> shift) & max;
+            samples.push((sample * 255 / max) as u8);
+        }
     }
 
-    // calculate the transformation matrix m such that a*m = b
-    let cs = a.solve(b).unwrap();
-    let mut data = cs.into_vec();
-    data.push(1.0);
-    Matrix::<f64>::new(3, 3, data).inverse().unwrap()
+    samples
+}
+
+/// Converts 8 bit CMYK samples, where higher means more ink, into RGB
+fn rgb_from_cmyk(samples: &[u8]) -> Vec<u8> {
+    samples.chunks_exact(4)
+        .flat_map(|p| {
+            let white = 255 - p[3] as u32;
+            let ink = move |c: u8| ((255 - c as u32) * white / 255) as u8;
+            [ink(p[0]), ink(p[1]), ink(p[2])]
+        })
+        .collect()
+}
+
+/// Converts 8 bit samples with 1 (gray), 2 (gray and alpha), 3 (RGB), or 4 (RGBA) channels per pixel into RGB,
+/// compositing anything transparent onto white paper.
+pub(crate) fn rgb_from_samples(samples: &[u8], channels: usize) -> Vec<u8> {
+    fn over_white(value: u8, alpha: u8) -> u8 {
+        let (value, alpha) = (value as u32, alpha as u32);
+        ((value*alpha + 255*(255-alpha) + 127) / 255) as u8
+    }
+
+    let mut data = Vec::with_capacity(samples.len() / channels * 3);
+
+    for pixel in samples.chunks_exact(channels) {
+        let (r, g, b, alpha) = match *pixel {
+            [v] => (v, v, v, 255),
+            [v, a] => (v, v, v, a),
+            [r, g, b] => (r, g, b, 255),
+            [r, g, b, a] => (r, g, b, a),
+            _ => unreachable!("images have between 1 and 4 channels"),
+        };
+
+        data.push(over_white(r, alpha));
+        data.push(over_white(g, alpha));
+        data.push(over_white(b, alpha));
+    }
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 3 by 2 image with each pixel's position in its red and green channels
+    fn upright() -> Image {
+        Image::from_fn(3, 2, |x, y| Color::from_rgb(x as u8, y as u8, 0))
+    }
+
+    fn assert_same(a: &Image, b: &Image) {
+        assert_eq!((a.base, a.height), (b.base, b.height));
+        assert_eq!(a.data, b.data);
+    }
+
+    #[test]
+    fn orientation_turns_images_upright() {
+        let page = upright();
+        let at = |x: usize, y: usize| page.get_color(x, y);
+
+        // how a camera held each way stores the upright image
+        let stored = [
+            (1, upright()),
+            (2, Image::from_fn(3, 2, |x, y| at(2 - x, y))),
+            (3, Image::from_fn(3, 2, |x, y| at(2 - x, 1 - y))),
+            (4, Image::from_fn(3, 2, |x, y| at(x, 1 - y))),
+            (5, Image::from_fn(2, 3, |x, y| at(y, x))),
+            (6, Image::from_fn(2, 3, |x, y| at(2 - y, x))),
+            (7, Image::from_fn(2, 3, |x, y| at(2 - y, 1 - x))),
+            (8, Image::from_fn(2, 3, |x, y| at(y, 1 - x))),
+        ];
+
+        for (orientation, image) in stored {
+            assert_same(&image.oriented(orientation), &page);
+        }
+    }
+
+    /// A one page, 8 by 1 pixel, 1 bit TIFF that claims to use `compression`
+    fn bilevel_tiff(compression: u16) -> Vec<u8> {
+        let entries: [(u16, u16, u32); 8] = [
+            (256, 3, 8), // ImageWidth
+            (257, 3, 1), // ImageLength
+            (258, 3, 1), // BitsPerSample
+            (259, 3, compression as u32), // Compression
+            (262, 3, 0), // PhotometricInterpretation, white is zero
+            (273, 4, 8 + 2 + 8*12 + 4), // StripOffsets, just past the IFD
+            (278, 3, 1), // RowsPerStrip
+            (279, 4, 1), // StripByteCounts
+        ];
+
+        let mut bytes = b"II*\0".to_vec();
+        bytes.extend(8u32.to_le_bytes());
+        bytes.extend((entries.len() as u16).to_le_bytes());
+        for (tag, kind, value) in entries {
+            bytes.extend(tag.to_le_bytes());
+            bytes.extend(kind.to_le_bytes());
+            bytes.extend(1u32.to_le_bytes());
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes.extend(0u32.to_le_bytes()); // no more pages
+        bytes.push(0b1010_0000);
+
+        bytes
+    }
+
+    fn read_tiff(name: &str, bytes: &[u8]) -> Result<Image, ScanError> {
+        let path = std::env::temp_dir().join(format!("picture_scout_{}_{}.tif", name, std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+        let image = Image::read_from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        image
+    }
+
+    #[test]
+    fn reads_uncompressed_bilevel_tiffs() {
+        let image = read_tiff("uncompressed", &bilevel_tiff(1)).unwrap();
+
+        assert_eq!((image.base, image.height), (8, 1));
+        let grays: Vec<u8> = (0..8).map(|x| image.get_color(x, 0).gray()).collect();
+        assert_eq!(grays, [0, 255, 0, 255, 255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn group_3_fax_tiffs_are_unsupported() {
+        for compression in [2, 3] {
+            match read_tiff("group3", &bilevel_tiff(compression)) {
+                Err(ScanError::UnsupportedPixelFormat(format)) => assert!(format.contains("Group 4")),
+                other => panic!("expected an unsupported pixel format, got {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_orientations_are_left_alone() {
+        for orientation in [0, 9, 100] {
+            assert_same(&upright().oriented(orientation), &upright());
+        }
+    }
+
+    const INTERPOLATIONS: [Interpolation; 3] = [Interpolation::Nearest, Interpolation::Bilinear, Interpolation::Bicubic];
+
+    #[test]
+    fn interpolation_weights_sum_to_one() {
+        for i in 0..=10 {
+            let t = i as f64 / 10.0;
+            for weights in [linear_weights(t), cubic_weights(t)] {
+                assert!((weights.iter().sum::<f64>() - 1.0).abs() < 1e-12, "{:?} at {}", weights, t);
+            }
+        }
+    }
+
+    #[test]
+    fn sampling_on_a_pixel_gives_that_pixel() {
+        let image = Image::from_fn(4, 3, |x, y| Color::from_rgb(x as u8 * 60, y as u8 * 100, 200 - x as u8 * 30));
+
+        for interpolation in INTERPOLATIONS {
+            for y in 0..3 {
+                for x in 0..4 {
+                    let sampled = image.sample(x as f64, y as f64, interpolation).map(Color::rgb);
+                    assert_eq!(sampled, Some(image.get_color(x, y).rgb()), "{:?}", interpolation);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sampling_between_pixels_blends_them() {
+        let image = Image::from_fn(2, 1, |x, _| Color::from_rgb(x as u8 * 200, 0, 0));
+
+        let halfway = |interpolation| image.sample(0.5, 0.0, interpolation).map(Color::rgb);
+
+        assert_eq!(halfway(Interpolation::Nearest), Some([0, 0, 0]));
+        assert_eq!(halfway(Interpolation::Bilinear), Some([100, 0, 0]));
+        assert_eq!(halfway(Interpolation::Bicubic), Some([100, 0, 0]));
+        for interpolation in INTERPOLATIONS {
+            assert!(image.sample(2.5, 0.0, interpolation).is_none(), "{:?}", interpolation);
+        }
+    }
+
+    /// A 2 by 1 PNG of `samples`
+    fn png(color: ColorType, depth: BitDepth, samples: &[u8], palette: Option<(&[u8], &[u8])>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut encoder = Encoder::new(&mut bytes, 2, 1);
+        encoder.set_color(color);
+        encoder.set_depth(depth);
+        if let Some((palette, transparency)) = palette {
+            encoder.set_palette(palette);
+            encoder.set_trns(transparency);
+        }
+        encoder.write_header().unwrap().write_image_data(samples).unwrap();
+        bytes
+    }
+
+    fn rgbs(image: &Image) -> Vec<[u8; 3]> {
+        (0..image.base).map(|x| image.get_color(x, 0).rgb()).collect()
+    }
+
+    #[test]
+    fn gray_pngs_are_read_as_gray() {
+        let image = Image::read_png(png(ColorType::Grayscale, BitDepth::Eight, &[0x20, 0xD0], None).as_slice()).unwrap();
+
+        assert_eq!(rgbs(&image), [[0x20; 3], [0xD0; 3]]);
+    }
+
+    #[test]
+    fn gray_alpha_pngs_are_read_over_white() {
+        let samples = [0x00, 0xFF, 0x00, 0x00]; // opaque black, then fully transparent black
+        let image = Image::read_png(png(ColorType::GrayscaleAlpha, BitDepth::Eight, &samples, None).as_slice()).unwrap();
+
+        assert_eq!(rgbs(&image), [[0x00; 3], [0xFF; 3]]);
+    }
+
+    #[test]
+    fn palette_pngs_are_expanded() {
+        let palette = [0xC0, 0x20, 0x10, 0x00, 0x00, 0x00];
+        let transparency = [0xFF, 0x00]; // the second entry is see-through
+        let samples = [0b0100_0000]; // 2 bits a pixel: entry 1, then entry 0
+        let bytes = png(ColorType::Indexed, BitDepth::Two, &samples, Some((&palette, &transparency)));
+        let image = Image::read_png(bytes.as_slice()).unwrap();
+
+        assert_eq!(rgbs(&image), [[0xFF; 3], [0xC0, 0x20, 0x10]]);
+    }
+
+    #[test]
+    fn sixteen_bit_pngs_are_cut_down_to_eight() {
+        let samples = [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xFE, 0xDC, 0xBA, 0x98, 0x76, 0x54];
+        let image = Image::read_png(png(ColorType::Rgb, BitDepth::Sixteen, &samples, None).as_slice()).unwrap();
+
+        assert_eq!(rgbs(&image), [[0x12, 0x56, 0x9A], [0xFE, 0xBA, 0x76]]);
+    }
 }
@@ -1,66 +1,429 @@
+use crate::parse::alignment::Alignment;
 use crate::parse::image::Image;
+use crate::parse::homography::Homography;
+use crate::parse::page_map::PageMap;
 use crate::parse::boolean_matrix::BooleanMatrix;
-use crate::parse::target_mesh::TargetMesh;
+use crate::parse::target_mesh::{TargetMesh, AlignerCandidate};
+use crate::parse::scan_error::ScanError;
+use crate::parse::projection::BarFill;
+use crate::parse::scan_options::{ScanOptions, InkRole, ReadMode};
 
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+const MIN_WARP_SIZE: usize = 500; // a page smaller than this in the photo is still straightened out at this size
+const MAX_WARP_SIZE: usize = 4000; // and one bigger is shrunk to it, to keep the straightened out page from taking up too much memory
+
+pub mod alignment;
 mod boolean_matrix;
+pub mod homography;
 pub mod image;
+mod netpbm;
+mod page_map;
+pub(crate) mod projection;
+pub mod scan_error;
+pub mod scan_options;
 mod target;
 mod target_mesh;
+#[cfg(test)]
+pub(crate) mod test_sheets;
 
-use crate::make::scan_sheet_elements::{ALIGNER_OUTER_RADIUS};
-use crate::make::scan_sheet_layout::ALIGNER_DISTANCE_FROM_CORNER;
-
-const DARK_THRESHOLD: u8 = 110; // all pixels darker than this are target candidates
+use crate::make::scan_sheet_layout::{PageLayout, BarState};
 
+/// How much of each bar of a layout is dark on a photo of a sheet, and which bars that makes filled in.
 #[derive(Debug)]
 pub struct BarsFound {
-    pub bars: Vec<(f64, f64)>,
+    bars: Vec<(f64, f64)>,
+    overrides: Vec<(f64, f64)>, // bars filled in with an ink whose role is `InkRole::Override`
+    scores: Vec<f64>, // how much of each bar of the layout is dark in a mark ink, in layout order
+    states: Vec<BarState>, // how each bar of the layout reads in a mark ink
+    override_scores: Vec<f64>, // the same, in an override ink
+    override_states: Vec<BarState>,
+    alignment: Alignment,
+}
+
+/// The intermediate images produced while finding bars, in the order they were made
+#[derive(Default)]
+pub struct DebugImages {
+    images: Vec<(&'static str, DebugImage)>,
+}
+
+enum DebugImage {
+    Color(Image),
+    Threshold(BooleanMatrix), // kept as is, so it can be written as a PBM without going through an RGB image
+}
+
+impl DebugImages {
+    /// No images yet; pass this to `BarsFound::from_image_with_debug` to fill it
+    pub fn new() -> DebugImages {
+        DebugImages { images: Vec::new() }
+    }
+
+    fn add(&mut self, name: &'static str, image: Image) {
+        self.images.push((name, DebugImage::Color(image)));
+    }
+
+    fn add_threshold(&mut self, name: &'static str, matrix: BooleanMatrix) {
+        self.images.push((name, DebugImage::Threshold(matrix)));
+    }
+
+    /// Writes every image into `directory` as `<name>.png`, returning the paths written
+    pub fn output_to_dir(&self, directory: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::with_capacity(self.images.len());
+
+        for (name, image) in self.images.iter() {
+            let path = directory.join(format!("{}.png", name));
+            match *image {
+                DebugImage::Color(ref image) => image.output_to_file(&path)?,
+                DebugImage::Threshold(ref matrix) => matrix.as_image().output_to_file(&path)?,
+            }
+            paths.push(path);
+        }
+
+        Ok(paths)
+    }
+
+    /// Writes every image into `directory`, thresholded ones as `<name>.pbm` and the rest as `<name>.ppm`,
+    /// returning the paths written
+    pub fn output_netpbm_to_dir(&self, directory: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::with_capacity(self.images.len());
+
+        for (name, image) in self.images.iter() {
+            let path = match *image {
+                DebugImage::Color(ref image) => {
+                    let path = directory.join(format!("{}.ppm", name));
+                    image.output_ppm(BufWriter::new(File::create(&path)?))?;
+                    path
+                },
+                DebugImage::Threshold(ref matrix) => {
+                    let path = directory.join(format!("{}.pbm", name));
+                    matrix.output_pbm(BufWriter::new(File::create(&path)?))?;
+                    path
+                },
+            };
+            paths.push(path);
+        }
+
+        Ok(paths)
+    }
 }
 
 impl BarsFound {
-    pub fn from_image(input_image: &Image) -> BarsFound {
-        let target_candidates = BooleanMatrix::from_image(&input_image, DARK_THRESHOLD);
+    /// Finds the aligners in `input_image`, then the filled in bars of `layout` the way `options.read_mode` says.
+    pub fn from_image(input_image: &Image, layout: &PageLayout, options: &ScanOptions) -> Result<BarsFound, ScanError> {
+        BarsFound::find(input_image, layout, options, None)
+    }
 
-        target_candidates.as_image().output_to_file("bruh.png");
+    /// Same as `from_image`, but also keeps the intermediate images so the parse can be inspected
+    /// The intermediate images made before an error are still kept.
+    pub fn from_image_with_debug(input_image: &Image, layout: &PageLayout, options: &ScanOptions, debug: &mut DebugImages) -> Result<BarsFound, ScanError> {
+        BarsFound::find(input_image, layout, options, Some(debug))
+    }
+
+    fn find(input_image: &Image, layout: &PageLayout, options: &ScanOptions, mut debug: Option<&mut DebugImages>) -> Result<BarsFound, ScanError> {
+        let target_candidates = BooleanMatrix::from_image(input_image, options);
+
+        if let Some(ref mut debug) = debug {
+            debug.add_threshold("threshold", target_candidates.clone());
+        }
 
         let mesh = TargetMesh::from_matrix(&target_candidates);
-        let mut debug_image = input_image.clone();
-        mesh.add_to_image(&mut debug_image);
-        debug_image.output_to_file("debug.png");
-        let mut aligner_centers = mesh.get_aligner_centers();
 
+        if let Some(ref mut debug) = debug {
+            let mut targets_image = input_image.clone();
+            mesh.add_to_image(&mut targets_image);
+            debug.add("targets", targets_image);
+        }
+
+        let geometry = layout.geometry();
+
+        // the aligners are found as fractions of the image's sides, but we sample it in pixels
+        let aligners: Vec<AlignerCandidate> = mesh.get_aligner_candidates(geometry.fiducial_centers().len()).into_iter()
+            .map(|aligner| {
+                let (x, y) = aligner.center;
+                AlignerCandidate { center: (x * input_image.base as f64, y * input_image.height as f64), ..aligner }
+            })
+            .collect();
+
+        let targets: Vec<(f64, f64)> = mesh.get_other_centers().into_iter()
+            .map(|(x, y)| (x * input_image.base as f64, y * input_image.height as f64))
+            .collect();
+
+        let (page_map, alignment) = alignment::align(&aligners, &targets, geometry)?;
+
+        match options.read_mode {
+            ReadMode::Warp => Ok(BarsFound::find_warped(input_image, &page_map, alignment, layout, options, debug)),
+            ReadMode::Projection => Ok(BarsFound::find_projected(input_image, &target_candidates, &page_map, alignment, layout, options, debug)),
+        }
+    }
+
+    /// Straightens the page out, and measures how much of each bar of the layout is dark in it
+    fn find_warped(input_image: &Image, page_map: &PageMap, alignment: Alignment, layout: &PageLayout, options: &ScanOptions, debug: Option<&mut DebugImages>) -> BarsFound {
+        // as big as the page is in the photo, so that thin bars aren't shrunk into a blur of their edges
+        let corners = [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)].map(|corner| page_map.map(corner));
+        let longest_side = (0..4)
+            .map(|i| {
+                let ((x0, y0), (x1, y1)) = (corners[i], corners[(i + 1) % 4]);
+                (x1 - x0).hypot(y1 - y0)
+            })
+            .fold(0.0, f64::max);
+        let new_image_height = if longest_side.is_finite() { longest_side.ceil() as usize } else { 0 }
+            .clamp(MIN_WARP_SIZE, MAX_WARP_SIZE);
+        let scale = new_image_height as f64;
+
+        let new_image_to_input = |(x, y): (f64, f64)| page_map.map((x / scale, y / scale));
+        let transformed_image = input_image.warp_by(new_image_to_input, new_image_height, new_image_height, options.interpolation);
+        let transformed_image_matrix = BooleanMatrix::from_image(&transformed_image, options);
+
+        // the bars are measured where they were printed, now that the page is straight
+        let page_to_new_image = PageMap::from_homography(Homography::scaling(scale, scale).expect("the new image has a size"));
+
+        let rects = layout.bar_rects();
+        let slack = layout.geometry().bar_distance_threshold / 2.0; // as far off as the alignment is trusted to be
+        let fills: Vec<BarFill> = rects.iter()
+            .map(|rect| BarFill::measure(rect, slack, &rects, &page_to_new_image, &transformed_image_matrix, &transformed_image, &options.ink_classes))
+            .collect();
+
+        if let Some(debug) = debug {
+            let mut transformed_bars_image = transformed_image.clone();
+            for (rect, fill) in rects.iter().zip(fills.iter()) {
+                fill.add_to_image(rect, &page_to_new_image, &transformed_image_matrix, &options.fill_thresholds, &mut transformed_bars_image);
+            }
+            debug.add("transformed", transformed_image);
+            debug.add("transformed_bars", transformed_bars_image);
+        }
+
+        BarsFound::from_fills(&fills, alignment, options)
+    }
 
+    /// Measures how much of each bar of the layout is dark where it lands in the photo
+    #[allow(clippy::too_many_arguments)]
+    fn find_projected(input_image: &Image, dark: &BooleanMatrix, page_map: &PageMap, alignment: Alignment, layout: &PageLayout, options: &ScanOptions, debug: Option<&mut DebugImages>) -> BarsFound {
+        let rects = layout.bar_rects();
+        let slack = layout.geometry().bar_distance_threshold / 2.0; // as far off as the alignment is trusted to be
+        let fills: Vec<BarFill> = rects.iter()
+            .map(|rect| BarFill::measure(rect, slack, &rects, page_map, dark, input_image, &options.ink_classes))
+            .collect();
 
+        if let Some(debug) = debug {
+            let mut projected_image = input_image.clone();
+            for (rect, fill) in rects.iter().zip(fills.iter()) {
+                fill.add_to_image(rect, page_map, dark, &options.fill_thresholds, &mut projected_image);
+            }
+            debug.add("projected", projected_image);
+        }
 
-        let d = ALIGNER_OUTER_RADIUS+ALIGNER_DISTANCE_FROM_CORNER;
-        let mut destination_centers = [(d, d), (1.0-d, d), (1.0-d, 1.0-d), (d, 1.0-d)];
+        BarsFound::from_fills(&fills, alignment, options)
+    }
 
+    /// Splits `fills`, one for each bar of the layout, by the role of their ink
+    fn from_fills(fills: &[BarFill], alignment: Alignment, options: &ScanOptions) -> BarsFound {
+        // bars in another ink read as empty
+        let read = |role: InkRole| -> (Vec<f64>, Vec<BarState>) {
+            fills.iter()
+                .map(|fill| if fill.role(&options.ink_classes) == role {
+                    (fill.fraction, fill.state(&options.fill_thresholds))
+                } else {
+                    (0.0, BarState::Unset)
+                })
+                .unzip()
+        };
+        let (scores, states) = read(InkRole::Mark);
+        let (override_scores, override_states) = read(InkRole::Override);
 
-        dbg!();
+        let centers = |states: &[BarState]| -> Vec<(f64, f64)> {
+            fills.iter().zip(states.iter())
+                .filter(|&(_, &state)| state == BarState::Set)
+                .map(|(fill, _)| fill.center)
+                .collect()
+        };
 
-        let new_image_height = 500; // why not?
-        // we have to scale our transformation centers to the size of the new image
-        for (x, y) in aligner_centers.iter_mut() {
-            *x *= input_image.base as f64;
-            *y *= input_image.height as f64;
+        BarsFound {
+            bars: centers(&states),
+            overrides: centers(&override_states),
+            scores,
+            states,
+            override_scores,
+            override_states,
+            alignment,
         }
-        for (x, y) in destination_centers.iter_mut() {
-            *x *= new_image_height as f64;
-            *y *= new_image_height as f64;
+    }
+
+    /// Bars in a mark ink read as `states`, with no overrides, as if measured with full or no scores
+    #[cfg(test)]
+    pub(crate) fn from_states(states: Vec<BarState>) -> BarsFound {
+        BarsFound {
+            bars: Vec::new(),
+            overrides: Vec::new(),
+            scores: states.iter().map(|&state| if state == BarState::Set { 1.0 } else { 0.0 }).collect(),
+            override_scores: vec![0.0; states.len()],
+            override_states: vec![BarState::Unset; states.len()],
+            states,
+            alignment: Alignment::exact(),
         }
+    }
+
+    /// Same bars, with those in an override ink reading as `override_states`
+    #[cfg(test)]
+    pub(crate) fn with_override_states(self, override_states: Vec<BarState>) -> BarsFound {
+        BarsFound {
+            // only whether there are any is used when reading them, not where they are
+            overrides: override_states.iter().filter(|&&state| state == BarState::Set).map(|_| (0.0, 0.0)).collect(),
+            override_scores: override_states.iter().map(|&state| if state == BarState::Set { 1.0 } else { 0.0 }).collect(),
+            override_states,
+            ..self
+        }
+    }
+
+    /// The centers of the filled in bars, as fractions of the page side
+    pub fn bars(&self) -> &[(f64, f64)] {
+        &self.bars
+    }
 
+    /// The centers of the bars filled in with an override ink, like a teacher's red pen
+    pub fn overrides(&self) -> &[(f64, f64)] {
+        &self.overrides
+    }
 
-        let transformed_image = input_image.perspective_transform(&aligner_centers, &destination_centers, new_image_height, new_image_height);
-        transformed_image.output_to_file("transformed.png");
-        let transformed_image_matrix = BooleanMatrix::from_image(&transformed_image, DARK_THRESHOLD);
+    /// How much of each bar of the layout is dark in a mark ink, from 0 to 1, in the order the bars were laid out
+    pub fn scores(&self) -> &[f64] {
+        &self.scores
+    }
 
-        let new_target_mesh = TargetMesh::from_matrix(&transformed_image_matrix);
+    /// How each bar of the layout reads in a mark ink, in the order the bars were laid out
+    pub fn states(&self) -> &[BarState] {
+        &self.states
+    }
 
-        let mut output_image_2 = transformed_image.clone();
-        new_target_mesh.add_to_image(&mut output_image_2);
-        output_image_2.output_to_file("debug2.png");
+    /// How well the fiducials found agreed with each other
+    pub fn alignment(&self) -> &Alignment {
+        &self.alignment
+    }
 
-        BarsFound { bars: new_target_mesh.get_bar_centers() }
+    /// The override bars on their own, to be read like a sheet
+    pub(crate) fn overrides_as_marks(&self) -> BarsFound {
+        BarsFound {
+            bars: self.overrides.clone(),
+            overrides: Vec::new(),
+            scores: self.override_scores.clone(),
+            states: self.override_states.clone(),
+            override_scores: vec![0.0; self.override_scores.len()],
+            override_states: vec![BarState::Unset; self.override_states.len()],
+            alignment: self.alignment,
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::make::scan_sheet_layout::{HighLevelPageDescription, HighLevelField, HighLevelKind, LayoutResult, LayoutResultOption};
+    use crate::parse::image::Color;
+    use crate::parse::scan_options::InkClass;
+    use crate::parse::test_sheets::{photograph, centered, PENCIL, RED_PEN};
+
+    /// A yes or no question and a two digit number, on a page with timing tracks
+    fn layout() -> PageLayout {
+        HighLevelPageDescription {
+            document_title: "test".to_string(),
+            fields: vec![
+                HighLevelField { kind: HighLevelKind::Boolean, descriptor: "yes".to_string() },
+                HighLevelField { kind: HighLevelKind::SevenSegmentDisplay(2), descriptor: "number".to_string() },
+            ],
+            edge_fiducials: false,
+            timing_tracks: true,
+        }.layout()
+    }
+
+    #[test]
+    fn timing_marks_are_used_on_a_page_smaller_than_the_photo() {
+        let layout = layout();
+        let (base, height) = (1600, 1200);
+        let image = photograph(&layout, &[(0, PENCIL)], base, height, &centered(base, height, 0.7));
+
+        let found = BarsFound::from_image(&image, &layout, &ScanOptions::default()).unwrap();
+
+        assert!(found.alignment().timing_marks_used() > 0);
+        assert_eq!(found.states()[0], BarState::Set);
+    }
+
+    #[test]
+    fn filled_bars_score_near_one_by_default() {
+        let layout = layout();
+        // the yes bar, and a 7 in the first digit, which is its top and both right bars
+        let filled = [0, 1, 2, 3];
+        let marks: Vec<(usize, Color)> = filled.iter().map(|&bar| (bar, PENCIL)).collect();
+        let (base, height) = (1600, 1200);
+        let image = photograph(&layout, &marks, base, height, &centered(base, height, 0.9));
+
+        let found = BarsFound::from_image(&image, &layout, &ScanOptions::default()).unwrap();
+
+        // all but a row or column of pixels at the edge, where the page can be placed a pixel off
+        for (bar, &score) in found.scores().iter().enumerate() {
+            if filled.contains(&bar) {
+                assert!(score > 0.85, "bar {} scored {}", bar, score);
+            } else {
+                assert!(score < 0.1, "bar {} scored {}", bar, score);
+            }
+        }
+    }
+
+    #[test]
+    fn both_read_modes_agree() {
+        let layout = layout();
+        // yes, and 71: the top and both right bars of the first digit, and both right bars of the second
+        let marks: Vec<(usize, Color)> = [0, 1, 2, 3, 9, 10].iter().map(|&bar| (bar, PENCIL)).collect();
+        let (base, height) = (1600, 1200);
+        let image = photograph(&layout, &marks, base, height, &centered(base, height, 0.9));
+
+        let read = |read_mode| {
+            let found = BarsFound::from_image(&image, &layout, &ScanOptions { read_mode, ..ScanOptions::default() }).unwrap();
+            let fields: Vec<String> = layout.interpret_targets(&found).fields().map(|(_, value)| value.to_string()).collect();
+            (found.states().to_vec(), fields)
+        };
+        let (warped, projected) = (read(ReadMode::Warp), read(ReadMode::Projection));
+
+        assert_eq!(warped.0, projected.0);
+        assert_eq!(warped.1, ["true", "71"]);
+        assert_eq!(projected.1, ["true", "71"]);
+    }
+
+    /// Pencil and blue pen as marks, and red pen in `red_pen`, like `--red-pen`
+    fn options_with_red_pen(red_pen: InkRole) -> ScanOptions {
+        ScanOptions {
+            ink_classes: vec![InkClass::graphite(InkRole::Mark), InkClass::blue(InkRole::Mark), InkClass::red(red_pen)],
+            ..ScanOptions::default()
+        }
+    }
+
+    /// The yes bar in red pen, and a 1 in pencil in the last digit, its two right bars
+    fn read_with_red_pen(red_pen: InkRole) -> LayoutResult {
+        let layout = layout();
+        let marks = [(0, RED_PEN), (9, PENCIL), (10, PENCIL)];
+        let (base, height) = (1600, 1200);
+        let image = photograph(&layout, &marks, base, height, &centered(base, height, 0.9));
+
+        let found = BarsFound::from_image(&image, &layout, &options_with_red_pen(red_pen)).unwrap();
+        layout.interpret_targets(&found)
+    }
+
+    #[test]
+    fn red_pen_overrides_are_read_apart_from_the_marks() {
+        let result = read_with_red_pen(InkRole::Override);
+
+        let fields: Vec<_> = result.fields().collect();
+        assert!(matches!(fields[..], [("yes", LayoutResultOption::Boolean(false)), ("number", LayoutResultOption::Number(1))]), "{:?}", fields);
+        let overrides: Vec<_> = result.overrides().collect();
+        assert!(matches!(overrides[..], [("yes", LayoutResultOption::Boolean(true))]), "{:?}", overrides);
+    }
+
+    #[test]
+    fn ignored_ink_reads_as_empty() {
+        let result = read_with_red_pen(InkRole::Ignore);
+
+        let fields: Vec<_> = result.fields().collect();
+        assert!(matches!(fields[..], [("yes", LayoutResultOption::Boolean(false)), ("number", LayoutResultOption::Number(1))]), "{:?}", fields);
+        assert_eq!(result.overrides().count(), 0);
+    }
+}
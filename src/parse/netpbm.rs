@@ -0,0 +1,337 @@
+use crate::parse::image::{Image, rgb_from_samples};
+use crate::parse::boolean_matrix::BooleanMatrix;
+use crate::parse::scan_error::ScanError;
+
+use std::io::{self, Read, Write};
+
+// http://netpbm.sourceforge.net/doc/pbm.html, pgm.html, and ppm.html
+// P1, P2, and P3 are the plain (ASCII) versions of P4, P5, and P6
+
+impl Image {
+    /// Writes the image as a binary PPM
+    pub fn output_ppm(&self, mut writer: impl Write) -> io::Result<()> {
+        write!(writer, "P6\n{} {}\n255\n", self.base, self.height)?;
+        writer.write_all(&self.data)?;
+        writer.flush()
+    }
+
+    /// Writes the image as a binary PGM, keeping only how light each pixel is
+    pub fn output_pgm(&self, mut writer: impl Write) -> io::Result<()> {
+        write!(writer, "P5\n{} {}\n255\n", self.base, self.height)?;
+
+        let mut row = Vec::with_capacity(self.base);
+        for y in 0..self.height {
+            row.clear();
+            row.extend((0..self.base).map(|x| self.get_color(x, y).gray()));
+            writer.write_all(&row)?;
+        }
+
+        writer.flush()
+    }
+
+    /// Reads a PBM, PGM, or PPM, in either their plain or binary versions and with any maximum value.
+    /// Only the first image of a file holding several is read.
+    pub(crate) fn read_netpbm(mut input: impl Read) -> Result<Image, ScanError> {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes)?;
+
+        let mut parser = Parser { bytes: &bytes, position: 0 };
+
+        if parser.byte() != Some(b'P') {
+            return Err(invalid("missing the magic number"));
+        }
+        let kind = parser.byte().ok_or_else(|| invalid("missing the magic number"))?;
+
+        let base = parser.number()?;
+        let height = parser.number()?;
+        let max_value = match kind {
+            b'1' | b'4' => 1,
+            _ => parser.number()?,
+        };
+
+        if !(1..=u16::MAX as usize).contains(&max_value) {
+            return Err(invalid("the maximum value must be between 1 and 65535"));
+        }
+
+        let too_large = || invalid("the image is too large");
+        let pixels = base.checked_mul(height).ok_or_else(too_large)?;
+        let rgb_samples = pixels.checked_mul(3).ok_or_else(too_large)?;
+
+        let (samples, channels) = match kind {
+            b'1' => (parser.plain_bits(pixels)?, 1),
+            b'2' => (parser.plain_samples(pixels, max_value)?, 1),
+            b'3' => (parser.plain_samples(rgb_samples, max_value)?, 3),
+            b'4' => (parser.raw_bits(base, height)?, 1),
+            b'5' => (parser.raw_samples(pixels, max_value)?, 1),
+            b'6' => (parser.raw_samples(rgb_samples, max_value)?, 3),
+            _ => return Err(invalid("unknown magic number")),
+        };
+
+        Ok(Image::from_raw(rgb_from_samples(&samples, channels), base, height))
+    }
+}
+
+impl BooleanMatrix {
+    /// Writes the matrix as a binary PBM, with true as black
+    pub fn output_pbm(&self, mut writer: impl Write) -> io::Result<()> {
+        let (base, height) = self.base_height();
+        write!(writer, "P4\n{} {}\n", base, height)?;
+
+        let mut row = vec![0; base.div_ceil(8)];
+        for y in 0..height {
+            row.iter_mut().for_each(|byte| *byte = 0);
+            for x in (0..base).filter(|&x| self.is_set(x, y)) {
+                row[x / 8] |= 0x80 >> (x % 8);
+            }
+            writer.write_all(&row)?;
+        }
+
+        writer.flush()
+    }
+}
+
+fn invalid(reason: &str) -> ScanError {
+    ScanError::Netpbm(String::from(reason))
+}
+
+/// Scales a sample from 0 to `max_value` into 0 to 255
+fn scale(sample: usize, max_value: usize) -> Result<u8, ScanError> {
+    if sample > max_value {
+        return Err(invalid("a sample is larger than the maximum value"));
+    }
+
+    Ok(((sample*255 + max_value/2) / max_value) as u8)
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.bytes.get(self.position)?;
+        self.position += 1;
+        Some(byte)
+    }
+
+    /// How many bytes are left, which bounds how many samples a plain image can still have, whatever its header says
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+
+    /// Skips whitespace, and comments from `#` to the end of the line
+    fn skip_whitespace(&mut self) {
+        while let Some(&byte) = self.bytes.get(self.position) {
+            if byte == b'#' {
+                while !matches!(self.byte(), Some(b'\n') | Some(b'\r') | None) {}
+            } else if byte.is_ascii_whitespace() {
+                self.position += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, ScanError> {
+        self.skip_whitespace();
+
+        let start = self.position;
+        while self.bytes.get(self.position).is_some_and(|b| b.is_ascii_digit()) {
+            self.position += 1;
+        }
+
+        std::str::from_utf8(&self.bytes[start..self.position]).ok()
+            .and_then(|digits| digits.parse().ok())
+            .ok_or_else(|| invalid("expected a number"))
+    }
+
+    /// The binary raster starts after exactly one whitespace character following the header
+    fn raster(&mut self, length: usize) -> Result<&'a [u8], ScanError> {
+        if !self.byte().is_some_and(|b| b.is_ascii_whitespace()) {
+            return Err(invalid("expected whitespace before the image data"));
+        }
+
+        let start = self.position;
+        let end = start.checked_add(length)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| invalid("the image data ends early"))?;
+
+        self.position = end;
+        Ok(&self.bytes[start..end])
+    }
+
+    /// Plain PBM digits don't need whitespace between them, and 1 means black
+    fn plain_bits(&mut self, count: usize) -> Result<Vec<u8>, ScanError> {
+        let mut samples = Vec::with_capacity(count.min(self.remaining()));
+
+        while samples.len() < count {
+            self.skip_whitespace();
+            match self.byte() {
+                Some(b'0') => samples.push(255),
+                Some(b'1') => samples.push(0),
+                Some(_) => return Err(invalid("expected a 0 or a 1")),
+                None => return Err(invalid("the image data ends early")),
+            }
+        }
+
+        Ok(samples)
+    }
+
+    fn plain_samples(&mut self, count: usize, max_value: usize) -> Result<Vec<u8>, ScanError> {
+        let mut samples = Vec::with_capacity(count.min(self.remaining()));
+
+        while samples.len() < count {
+            samples.push(scale(self.number()?, max_value)?);
+        }
+
+        Ok(samples)
+    }
+
+    /// Each row of a binary PBM starts on a new byte, with the leftmost pixel in the high bit
+    fn raw_bits(&mut self, base: usize, height: usize) -> Result<Vec<u8>, ScanError> {
+        let row_bytes = base.div_ceil(8);
+        let raster = self.raster(row_bytes.checked_mul(height).ok_or_else(|| invalid("the image is too large"))?)?;
+
+        let mut samples = Vec::with_capacity(base*height);
+
+        for row in raster.chunks(row_bytes.max(1)).take(height) {
+            for x in 0..base {
+                let black = row[x / 8] & (0x80 >> (x % 8)) != 0;
+                samples.push(if black { 0 } else { 255 });
+            }
+        }
+
+        Ok(samples)
+    }
+
+    /// Samples take two bytes, most significant first, if the maximum value doesn't fit in one
+    fn raw_samples(&mut self, count: usize, max_value: usize) -> Result<Vec<u8>, ScanError> {
+        let sample_bytes = if max_value < 256 { 1 } else { 2 };
+        let raster = self.raster(count.checked_mul(sample_bytes).ok_or_else(|| invalid("the image is too large"))?)?;
+
+        raster.chunks_exact(sample_bytes)
+            .map(|sample| {
+                let sample = sample.iter().fold(0, |total, &byte| total*256 + byte as usize);
+                scale(sample, max_value)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(bytes: &[u8]) -> Result<Image, ScanError> {
+        Image::read_netpbm(bytes)
+    }
+
+    /// The red, green, and blue channels of every pixel, row by row
+    fn channels(image: &Image) -> Vec<[u8; 3]> {
+        (0..image.height).flat_map(|y| (0..image.base).map(move |x| (x, y))).map(|(x, y)| image.get_color(x, y).rgb()).collect()
+    }
+
+    fn grays(image: &Image) -> Vec<u8> {
+        channels(image).into_iter().map(|[r, _, _]| r).collect()
+    }
+
+    fn assert_invalid(bytes: &[u8]) {
+        match read(bytes) {
+            Err(ScanError::Netpbm(_)) => {},
+            other => panic!("expected a Netpbm error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn plain_pbm() {
+        // the digits of a plain PBM don't need whitespace between them
+        let image = read(b"P1\n# a comment\n3 2\n1 0 1\n010").unwrap();
+        assert_eq!((image.base, image.height), (3, 2));
+        assert_eq!(grays(&image), [0, 255, 0, 255, 0, 255]);
+    }
+
+    #[test]
+    fn plain_pgm() {
+        let image = read(b"P2 2 2 10\n0 5\n10 # the rest of the line is a comment\n 2").unwrap();
+        assert_eq!(grays(&image), [0, 128, 255, 51]);
+    }
+
+    #[test]
+    fn plain_ppm() {
+        let image = read(b"P3 2 1 255 255 0 0 1 2 3").unwrap();
+        assert_eq!(channels(&image), [[255, 0, 0], [1, 2, 3]]);
+    }
+
+    #[test]
+    fn plain_samples_above_255() {
+        let image = read(b"P2 3 1 65535 0 32768 65535").unwrap();
+        assert_eq!(grays(&image), [0, 128, 255]);
+    }
+
+    #[test]
+    fn raw_pbm() {
+        // rows start on a new byte, with the leftmost pixel in the high bit
+        let image = read(b"P4 10 2\n\xc0\x40\x00\xff").unwrap();
+        assert_eq!(grays(&image), [0, 0, 255, 255, 255, 255, 255, 255, 255, 0, 255, 255, 255, 255, 255, 255, 255, 255, 0, 0]);
+    }
+
+    #[test]
+    fn raw_pgm() {
+        let image = read(b"P5 3 1 255\n\x00\x80\xff").unwrap();
+        assert_eq!(grays(&image), [0, 128, 255]);
+    }
+
+    #[test]
+    fn raw_samples_above_255() {
+        // two bytes a sample, most significant first
+        let image = read(b"P5 3 1 1000\n\x00\x00\x01\xf4\x03\xe8").unwrap();
+        assert_eq!(grays(&image), [0, 128, 255]);
+
+        let image = read(b"P6 1 1 65535\n\xff\xff\x00\x00\x80\x00").unwrap();
+        assert_eq!(channels(&image), [[255, 0, 128]]);
+    }
+
+    #[test]
+    fn raw_ppm() {
+        let image = read(b"P6 2 1 255\n\x01\x02\x03\x04\x05\x06").unwrap();
+        assert_eq!(channels(&image), [[1, 2, 3], [4, 5, 6]]);
+    }
+
+    #[test]
+    fn bad_headers() {
+        assert_invalid(b"P7 1 1 255\n\x00");
+        assert_invalid(b"P5 1 1\n");
+        assert_invalid(b"P5 1 1 0\n\x00");
+        assert_invalid(b"P5 1 1 65536\n\x00\x00");
+        assert_invalid(b"P2 2 1 10 0 11");
+    }
+
+    #[test]
+    fn truncated_data() {
+        assert_invalid(b"P1 3 2 1 0 1 0");
+        assert_invalid(b"P2 2 2 255 0 1 2");
+        assert_invalid(b"P3 1 1 255 0 0");
+        assert_invalid(b"P4 9 2\n\x00\x00\x00");
+        assert_invalid(b"P5 2 2 255\n\x00\x00\x00");
+        assert_invalid(b"P5 2 1 1000\n\x00\x00\x00");
+        assert_invalid(b"P6 1 1 255\n\x00\x00");
+    }
+
+    #[test]
+    fn oversized_headers() {
+        // none of these should try to make room for the image the header describes before finding the data missing
+        assert_invalid(b"P1 4000000000 4000000000\n0");
+        assert_invalid(b"P2 4000000000 4000000000 255\n0");
+        assert_invalid(b"P3 4000000000 4000000000 255\n0 0 0");
+        assert_invalid(b"P4 4000000000 4000000000\n\x00");
+        assert_invalid(b"P5 4000000000 4000000000 255\n\x00");
+        assert_invalid(b"P6 4000000000 4000000000 65535\n\x00");
+
+        // so big that the number of samples or bytes doesn't fit
+        assert_invalid(b"P3 4294967296 2147483648 255\n0 0 0");
+        assert_invalid(b"P6 4294967296 2147483648 255\n\x00");
+        assert_invalid(b"P5 18446744073709551615 2 255\n\x00");
+        assert_invalid(b"P5 99999999999999999999 1 255\n\x00");
+    }
+}
@@ -0,0 +1,309 @@
+use crate::make::scan_sheet_layout::TimingTracks;
+use crate::parse::homography::Homography;
+
+const TIMING_SEARCH_DISTANCE: f64 = 0.02; // how far from where the marks before it say it should be, as a fraction of the page side, a timing mark can be found
+const TIMING_OUTLIER_DISTANCE: f64 = 0.005; // marks found further than this from where the marks around them say they should be are left out
+const TIMING_WALK: usize = 3; // how many of the marks before it say where a mark should be
+const TIMING_NEIGHBORHOOD: f64 = 0.12; // a mark is checked against the others this close to it along the track, two each side as printed
+const INVERSE_ITERATIONS: usize = 5; // the correction changes slowly across the page, so this settles quickly
+
+/// Where each point of the page is in the image. The aligners fix a perspective transformation, which timing marks,
+/// if the page has them, then bend locally to follow curled or wavy paper.
+#[derive(Clone, Debug)]
+pub(crate) struct PageMap {
+    homography: Homography,
+    correction: Option<Correction>,
+}
+
+impl PageMap {
+    /// Just `homography`, with nothing bending it
+    pub fn from_homography(homography: Homography) -> PageMap {
+        PageMap { homography, correction: None }
+    }
+
+    /// Bends `homography` to go through the timing marks of `tracks` that are found among `targets`, the centers of
+    /// the targets in the image in pixels. Returns how many marks were found along with the map.
+    pub fn with_timing_marks(homography: Homography, tracks: &TimingTracks, targets: &[(f64, f64)]) -> (PageMap, usize) {
+        let on_page: Vec<(f64, f64)> = targets.iter().map(|&target| homography.map_inverse(target)).collect();
+        let (near, far) = (tracks.inset, 1.0 - tracks.inset);
+
+        let correction = Correction {
+            near,
+            far,
+            top: Track::measure(&tracks.positions, |x| (x, near), &on_page),
+            bottom: Track::measure(&tracks.positions, |x| (x, far), &on_page),
+            left: Track::measure(&tracks.positions, |y| (near, y), &on_page),
+            right: Track::measure(&tracks.positions, |y| (far, y), &on_page),
+        };
+
+        let found = [&correction.top, &correction.bottom, &correction.left, &correction.right].iter()
+            .map(|track| track.offsets.len())
+            .sum();
+
+        (PageMap { homography, correction: Some(correction) }, found)
+    }
+
+    /// Where `point` on the page is in the image
+    pub fn map(&self, point: (f64, f64)) -> (f64, f64) {
+        match self.correction {
+            Some(ref correction) => {
+                let (dx, dy) = correction.offset(point);
+                self.homography.map((point.0 + dx, point.1 + dy))
+            },
+            None => self.homography.map(point),
+        }
+    }
+
+    /// Where `point` in the image is on the page
+    pub fn map_inverse(&self, point: (f64, f64)) -> (f64, f64) {
+        let (x, y) = self.homography.map_inverse(point);
+
+        match self.correction {
+            Some(ref correction) => {
+                // find the page point that the correction moves onto (x, y)
+                let mut guess = (x, y);
+                for _ in 0..INVERSE_ITERATIONS {
+                    let (dx, dy) = correction.offset(guess);
+                    guess = (x - dx, y - dy);
+                }
+                guess
+            },
+            None => (x, y),
+        }
+    }
+}
+
+/// How far each point of the page is from where the homography alone puts it, as measured by the timing marks along
+/// the four edges. The inside of the page is filled in from the edges with a Coons patch.
+#[derive(Clone, Debug)]
+struct Correction {
+    near: f64, // the left and top tracks are this far into the page
+    far: f64, // and the right and bottom ones this far
+    top: Track,
+    bottom: Track,
+    left: Track,
+    right: Track,
+}
+
+impl Correction {
+    fn offset(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        let span = self.far - self.near;
+        let u = ((x - self.near) / span).clamp(0.0, 1.0);
+        let v = ((y - self.near) / span).clamp(0.0, 1.0);
+
+        let (top, bottom, left, right) = (self.top.at(x), self.bottom.at(x), self.left.at(y), self.right.at(y));
+        // the tracks meeting at a corner each say where it is, which may not quite agree
+        let corner = |a: (f64, f64), b: (f64, f64)| ((a.0 + b.0) / 2.0, (a.1 + b.1) / 2.0);
+        let top_left = corner(self.top.at(self.near), self.left.at(self.near));
+        let top_right = corner(self.top.at(self.far), self.right.at(self.near));
+        let bottom_left = corner(self.bottom.at(self.near), self.left.at(self.far));
+        let bottom_right = corner(self.bottom.at(self.far), self.right.at(self.far));
+
+        let blend = |top: f64, bottom: f64, left: f64, right: f64, top_left: f64, top_right: f64, bottom_left: f64, bottom_right: f64| {
+            (1.0-v)*top + v*bottom + (1.0-u)*left + u*right
+                - ((1.0-u)*(1.0-v)*top_left + u*(1.0-v)*top_right + (1.0-u)*v*bottom_left + u*v*bottom_right)
+        };
+
+        (
+            blend(top.0, bottom.0, left.0, right.0, top_left.0, top_right.0, bottom_left.0, bottom_right.0),
+            blend(top.1, bottom.1, left.1, right.1, top_left.1, top_right.1, bottom_left.1, bottom_right.1),
+        )
+    }
+}
+
+/// The offsets of the marks found along one track
+#[derive(Clone, Debug)]
+struct Track {
+    offsets: Vec<(f64, (f64, f64))>, // where the mark is along the track, and how far it was found from where it should be
+}
+
+impl Track {
+    /// Looks for each mark in turn, with `mark_at` giving where on the page the mark at a position along the track is.
+    /// The paper bends smoothly, so each mark is looked for about as far off as the few before it were found, and
+    /// marks found somewhere the marks around them disagree with, like a stray blot near the track, are left out.
+    fn measure(positions: &[f64], mark_at: impl Fn(f64) -> (f64, f64), on_page: &[(f64, f64)]) -> Track {
+        let mut found: Vec<(f64, (f64, f64))> = Vec::new();
+
+        for &position in positions.iter() {
+            let (x, y) = mark_at(position);
+            let expected = median(found.iter().rev().take(TIMING_WALK).map(|&(_, offset)| offset)).unwrap_or((0.0, 0.0));
+
+            let closest = on_page.iter()
+                .map(|&(target_x, target_y)| (target_x - x, target_y - y))
+                .map(|offset| (offset, (offset.0 - expected.0).hypot(offset.1 - expected.1)))
+                .filter(|&(_, distance)| distance < TIMING_SEARCH_DISTANCE)
+                .min_by(|a, b| a.1.total_cmp(&b.1));
+
+            if let Some((offset, _)) = closest {
+                found.push((position, offset));
+            }
+        }
+
+        // a stray blot near the track pulls the marks around it towards it too, so the worst is left out first
+        loop {
+            let worst = (0..found.len())
+                .filter_map(|i| Some((i, residual(&found, i)?)))
+                .max_by(|a, b| a.1.total_cmp(&b.1));
+
+            match worst {
+                Some((i, residual)) if residual > TIMING_OUTLIER_DISTANCE => { found.remove(i); },
+                _ => break,
+            }
+        }
+
+        Track { offsets: found }
+    }
+
+    /// The offset at `position`, linear between the marks found, and past the ends along the line through the last two.
+    /// Paper curls most at its corners, which this follows better than holding the offset of the last mark, though it
+    /// carries on any error in those two marks as well. Zero if none were found.
+    fn at(&self, position: f64) -> (f64, f64) {
+        let i = match self.offsets.len() {
+            0 => return (0.0, 0.0),
+            1 => return self.offsets[0].1,
+            len => self.offsets.iter().position(|&(p, _)| p >= position).unwrap_or(len - 1).clamp(1, len - 1),
+        };
+
+        let (p0, (x0, y0)) = self.offsets[i-1];
+        let (p1, (x1, y1)) = self.offsets[i];
+        let t = (position - p0) / (p1 - p0);
+
+        (x0 + t*(x1 - x0), y0 + t*(y1 - y0))
+    }
+}
+
+/// The median of each coordinate of `offsets`, or None if there aren't any
+fn median(offsets: impl Iterator<Item=(f64, f64)>) -> Option<(f64, f64)> {
+    let (mut xs, mut ys): (Vec<f64>, Vec<f64>) = offsets.unzip();
+    if xs.is_empty() {
+        return None;
+    }
+
+    let middle = |values: &mut Vec<f64>| {
+        values.sort_by(f64::total_cmp);
+        let half = values.len() / 2;
+        if values.len().is_multiple_of(2) { (values[half - 1] + values[half]) / 2.0 } else { values[half] }
+    };
+
+    Some((middle(&mut xs), middle(&mut ys)))
+}
+
+/// How far the offset of the `i`th of `found` is from the line through the offsets of the marks near it along the track,
+/// or None if there are too few of them to say
+fn residual(found: &[(f64, (f64, f64))], i: usize) -> Option<f64> {
+    let (position, (x, y)) = found[i];
+    let near: Vec<(f64, (f64, f64))> = found.iter().enumerate()
+        .filter(|&(j, &(p, _))| j != i && (p - position).abs() <= TIMING_NEIGHBORHOOD)
+        .map(|(_, &mark)| mark)
+        .collect();
+
+    if near.len() < 2 {
+        return None;
+    }
+
+    // least squares lines through the offsets, one for each coordinate
+    let n = near.len() as f64;
+    let mean_p = near.iter().map(|&(p, _)| p).sum::<f64>() / n;
+    let spread = near.iter().map(|&(p, _)| (p - mean_p).powi(2)).sum::<f64>();
+    let line = |coordinate: fn((f64, f64)) -> f64| {
+        let mean = near.iter().map(|&(_, offset)| coordinate(offset)).sum::<f64>() / n;
+        let slope = near.iter().map(|&(p, offset)| (p - mean_p) * (coordinate(offset) - mean)).sum::<f64>() / spread;
+        mean + slope * (position - mean_p)
+    };
+
+    Some((x - line(|o| o.0)).hypot(y - line(|o| o.1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POSITIONS: [f64; 9] = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9];
+
+    /// How far down a page curled like a wave has carried the top track at `x`
+    fn wave(x: f64) -> (f64, f64) {
+        (0.0, 0.01 * (std::f64::consts::PI * x).sin())
+    }
+
+    /// The marks of a top track at y = 0.05, where `offset` puts them
+    fn marks(offset: impl Fn(f64) -> (f64, f64)) -> Vec<(f64, f64)> {
+        POSITIONS.iter().map(|&x| (x + offset(x).0, 0.05 + offset(x).1)).collect()
+    }
+
+    fn measure(on_page: &[(f64, f64)]) -> Track {
+        Track::measure(&POSITIONS, |x| (x, 0.05), on_page)
+    }
+
+    fn assert_close(a: (f64, f64), b: (f64, f64)) {
+        assert!((a.0 - b.0).abs() < 1e-9 && (a.1 - b.1).abs() < 1e-9, "{:?} is not {:?}", a, b);
+    }
+
+    #[test]
+    fn tracks_follow_curled_paper() {
+        let track = measure(&marks(wave));
+
+        assert_eq!(track.offsets.len(), POSITIONS.len());
+        for &x in POSITIONS.iter() {
+            assert_close(track.at(x), wave(x));
+        }
+        // halfway between two marks
+        let (_, y) = track.at(0.45);
+        assert!((y - (wave(0.4).1 + wave(0.5).1) / 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn tracks_carry_on_past_their_ends() {
+        let track = measure(&marks(|x| (0.0, 0.02 * x)));
+
+        assert_close(track.at(0.0), (0.0, 0.0));
+        assert_close(track.at(1.0), (0.0, 0.02));
+        assert_close(Track { offsets: Vec::new() }.at(0.5), (0.0, 0.0));
+        assert_close(Track { offsets: vec![(0.5, (0.01, 0.02))] }.at(0.1), (0.01, 0.02));
+    }
+
+    #[test]
+    fn a_stray_blot_is_left_out() {
+        let mut on_page = marks(wave);
+        on_page[4] = (0.5, 0.05); // the mark in the middle is covered, and there's a blot where it would be if flat
+        on_page.push((0.6 + 0.012, 0.05 + wave(0.6).1)); // and another just beside the next mark
+
+        let track = measure(&on_page);
+
+        let found: Vec<f64> = track.offsets.iter().map(|&(p, _)| p).collect();
+        assert_eq!(found, [0.1, 0.2, 0.3, 0.4, 0.6, 0.7, 0.8, 0.9]);
+        for &x in POSITIONS.iter() {
+            let (dx, dy) = track.at(x);
+            assert!(dx.abs() < 1e-9 && (dy - wave(x).1).abs() < 0.001, "{} is off by {:?}", x, (dx, dy));
+        }
+    }
+
+    #[test]
+    fn a_blot_on_the_way_doesnt_lead_the_track_astray() {
+        let mut on_page = marks(|_| (0.0, 0.0));
+        on_page[3] = (0.4, 0.05 + 0.015); // found instead of the real mark, which is missing
+        on_page.extend(POSITIONS[4..].iter().map(|&x| (x, 0.05 + 0.028))); // blots that would be next, if it were right
+
+        let track = measure(&on_page);
+        assert!(track.offsets.iter().all(|&(_, offset)| offset == (0.0, 0.0)), "{:?}", track.offsets);
+        assert_eq!(track.offsets.len(), POSITIONS.len() - 1);
+    }
+
+    #[test]
+    fn map_inverse_undoes_map() {
+        let tracks = TimingTracks { inset: 0.05, mark_size: 0.015, positions: POSITIONS.to_vec() };
+        let page_to_image = Homography::scaling(1000.0, 1000.0).unwrap();
+
+        // every track pushed along by the wave, found where the photo shows them
+        let targets: Vec<(f64, f64)> = tracks.mark_centers()
+            .map(|(x, y)| page_to_image.map((x + wave(x + y).1, y + wave(y).1)))
+            .collect();
+
+        let (page_map, found) = PageMap::with_timing_marks(page_to_image, &tracks, &targets);
+        assert_eq!(found, 4 * POSITIONS.len());
+
+        for point in [(0.5, 0.5), (0.1, 0.8), (0.3, 0.2), (0.02, 0.98)] {
+            let (x, y) = page_map.map_inverse(page_map.map(point));
+            assert!((x - point.0).hypot(y - point.1) < 1e-6, "{:?} comes back as {:?}", point, (x, y));
+        }
+    }
+}
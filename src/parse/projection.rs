@@ -0,0 +1,368 @@
+use crate::parse::boolean_matrix::BooleanMatrix;
+use crate::parse::page_map::PageMap;
+use crate::parse::image::{Image, Color};
+use crate::make::scan_sheet_layout::BarState;
+use crate::parse::scan_options::{InkClass, InkRole, FillThresholds};
+
+const FILLED_COLOR: Color = Color::green();
+const EMPTY_COLOR: Color = Color::cyan();
+const AMBIGUOUS_COLOR: Color = Color::yellow();
+const ERASED_COLOR: Color = Color::blue();
+const CANCELLED_COLOR: Color = Color::red();
+
+const SHIFT_STEPS: usize = 5; // how many places across the slack, in each direction, a bar is measured at
+const NEIGHBOR_CLEARANCE: f64 = 0.003; // ink this close to another bar, as a fraction of the page side, may have spilled over from it
+
+const MARK_CONTRAST: f64 = 0.15; // pixels dark or at least this much darker than the paper around them, as a fraction of the paper's gray, are marked; the template is lighter
+const ERASED_COVERAGE: f64 = 0.5; // a bar at least this much marked was filled in, even if the marks are too faint to count as dark
+const ERASED_CONTRAST: f64 = 0.33; // and if its marks are on average less than this much darker than the paper, it was rubbed out
+const CANCEL_CLEARANCE: f64 = 0.009; // ink this far past the edges of a bar, as a fraction of the page side, is more than a sloppy fill
+const CANCEL_REACH: f64 = 0.02; // and it is looked for out to this far
+const CROSSING_INK: f64 = 0.01; // a stroke leaves a bar on one side if there's at least this much ink past its clearance there, compared to the bar's own area
+
+/// Where a bar is printed, as fractions of the page side
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct BarRect {
+    pub left: f64,
+    pub top: f64,
+    pub right: f64,
+    pub bottom: f64,
+}
+
+impl BarRect {
+    pub fn center(&self) -> (f64, f64) {
+        ((self.left + self.right) / 2.0, (self.top + self.bottom) / 2.0)
+    }
+
+    fn contains(&self, (x, y): (f64, f64)) -> bool {
+        (self.left..=self.right).contains(&x) && (self.top..=self.bottom).contains(&y)
+    }
+
+    fn shifted(&self, (dx, dy): (f64, f64)) -> BarRect {
+        BarRect { left: self.left + dx, top: self.top + dy, right: self.right + dx, bottom: self.bottom + dy }
+    }
+
+    fn grown(&self, by: f64) -> BarRect {
+        BarRect { left: self.left - by, top: self.top - by, right: self.right + by, bottom: self.bottom + by }
+    }
+
+    fn overlaps(&self, other: &BarRect) -> bool {
+        self.left <= other.right && other.left <= self.right && self.top <= other.bottom && other.top <= self.bottom
+    }
+}
+
+/// How much of a bar is dark in the photo, and how
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BarFill {
+    pub center: (f64, f64), // where the bar is printed, as fractions of the page side
+    pub fraction: f64, // of the bar's own pixels in the photo, how many are dark, wherever in the slack that is most
+    pub ink: Option<usize>, // which of the scan's ink classes the dark pixels' mean color matches, if any
+    pub erased: bool, // most of the bar is only a little darker than the paper around it, like rubbed out pencil
+    pub cancelled: bool, // strokes run through the bar and out past it on opposite sides, like an X scribbled over it
+}
+
+impl BarFill {
+    /// Looks at the pixels of `image` that `rect` lands on, with `dark` being `image` after thresholding. The alignment
+    /// can be off by a little, so `rect` is also tried shifted by up to `slack` each way, and the darkest place is kept.
+    /// Only the bar's own pixels are counted, leaving out those near any of `rects`, the other bars of the layout, so
+    /// that an empty bar can't be shifted onto a filled in one next to it. Ink around the bar counts towards crossing
+    /// it out, except near the other bars too.
+    pub fn measure(rect: &BarRect, slack: f64, rects: &[BarRect], page_map: &PageMap, dark: &BooleanMatrix, image: &Image, ink_classes: &[InkClass]) -> BarFill {
+        let search = rect.grown(slack + CANCEL_REACH);
+        let pixels: Vec<((f64, f64), Color, bool)> = window(&search, page_map, image)
+            .map(|(x, y)| (page_map.map_inverse((x as f64, y as f64)), image.get_color(x, y), dark.is_set(x, y)))
+            .collect();
+
+        // the shift of zero comes first, so it wins ties
+        let steps = (0..SHIFT_STEPS).map(|i| i as f64 / (SHIFT_STEPS - 1) as f64 * 2.0 - 1.0);
+        let mut shifts: Vec<(f64, f64)> = steps.clone()
+            .flat_map(|dx| steps.clone().map(move |dy| (dx * slack, dy * slack)))
+            .collect();
+        shifts.sort_by(|a, b| a.0.hypot(a.1).total_cmp(&b.0.hypot(b.1)));
+
+        // a shift towards a filled in bar next door would take in its ink, so what's around the other bars isn't counted
+        let neighbors: Vec<BarRect> = rects.iter()
+            .filter(|&other| other != rect)
+            .map(|other| other.grown(NEIGHBOR_CLEARANCE))
+            .filter(|other| other.overlaps(&search))
+            .collect();
+        let own = |point: (f64, f64)| !neighbors.iter().any(|other| other.contains(point));
+
+        let mut best: Option<(f64, BarRect)> = None;
+
+        for shift in shifts {
+            let shifted = rect.shifted(shift);
+            let (inside, dark_inside) = pixels.iter()
+                .filter(|&&(point, _, _)| shifted.contains(point) && own(point))
+                .fold((0, 0), |(inside, dark_inside), &(_, _, is_dark)| (inside + 1, dark_inside + is_dark as usize));
+
+            let fraction = if inside == 0 { 0.0 } else { dark_inside as f64 / inside as f64 };
+
+            if best.as_ref().is_none_or(|&(best_fraction, _)| fraction > best_fraction) {
+                best = Some((fraction, shifted));
+            }
+        }
+
+        let (fraction, placed) = best.expect("the shift of zero is always tried");
+
+        let area = pixels.iter().filter(|&&(point, _, _)| placed.contains(point) && own(point)).count();
+        let marks: Vec<Color> = pixels.iter()
+            .filter(|&&(point, _, is_dark)| is_dark && placed.contains(point) && own(point))
+            .map(|&(_, color, _)| color)
+            .collect();
+
+        // what's around the bar, clear of it and of every other bar
+        let clear = placed.grown(CANCEL_CLEARANCE);
+        let others: Vec<BarRect> = rects.iter()
+            .map(|other| other.grown(CANCEL_CLEARANCE))
+            .filter(|other| other.overlaps(&search))
+            .collect();
+        let around: Vec<&((f64, f64), Color, bool)> = pixels.iter()
+            .filter(|&&(point, _, _)| !clear.contains(point) && !others.iter().any(|other| other.contains(point)))
+            .collect();
+
+        // a sloppy fill spreads a little way all around a bar, but the strokes of a cross run well out past it, and out
+        // of opposite sides or corners
+        let mut ink_by_side = [[0usize; 3]; 3];
+        for &&((x, y), _, _) in around.iter().filter(|&&&(_, _, is_dark)| is_dark) {
+            let side = |v: f64, low: f64, high: f64| if v < low { 0 } else if v > high { 2 } else { 1 };
+            ink_by_side[side(y, placed.top, placed.bottom)][side(x, placed.left, placed.right)] += 1;
+        }
+        let leaves = |(row, column): (usize, usize)| area > 0 && ink_by_side[row][column] as f64 / area as f64 >= CROSSING_INK;
+        let cancelled = [((0, 0), (2, 2)), ((0, 2), (2, 0)), ((1, 0), (1, 2)), ((0, 1), (2, 1))].iter()
+            .any(|&(side, opposite)| leaves(side) && leaves(opposite));
+
+        // faint marks may not be dark enough to pass the threshold, and on dim paper even a solid fill may be little
+        // darker than the threshold, so erasing is told from the photo itself, against the paper around the bar
+        let erased = match median_gray(around.iter().map(|&&(_, color, _)| color)) {
+            Some(paper) if paper > 0.0 => {
+                let contrasts: Vec<f64> = pixels.iter()
+                    .filter(|&&(point, _, _)| placed.contains(point) && own(point))
+                    .map(|&(_, color, is_dark)| ((paper - color.gray() as f64) / paper, is_dark))
+                    .filter(|&(contrast, is_dark)| is_dark || contrast >= MARK_CONTRAST)
+                    .map(|(contrast, _)| contrast)
+                    .collect();
+
+                let coverage = if area == 0 { 0.0 } else { contrasts.len() as f64 / area as f64 };
+                let contrast = contrasts.iter().sum::<f64>() / contrasts.len().max(1) as f64;
+                coverage >= ERASED_COVERAGE && contrast < ERASED_CONTRAST
+            },
+            _ => false,
+        };
+
+        let ink = mean_color(&marks).and_then(|color| ink_classes.iter().position(|ink| ink.matches(color)));
+
+        BarFill { center: rect.center(), fraction, ink, erased, cancelled }
+    }
+
+    /// How the bar reads. Crossing out a bar only matters if it would otherwise have been filled in; a crossed out bar
+    /// that isn't is empty, as the strokes of the cross darken some of it on their own. A bar covered in faint marks
+    /// was erased however much of it is dark.
+    pub fn state(&self, fill_thresholds: &FillThresholds) -> BarState {
+        match fill_thresholds.state(self.fraction) {
+            BarState::Set if self.cancelled => BarState::Cancelled,
+            _ if self.cancelled => BarState::Unset,
+            _ if self.erased => BarState::Erased,
+            state => state,
+        }
+    }
+
+    /// Bars in no listed ink are marks
+    pub fn role(&self, ink_classes: &[InkClass]) -> InkRole {
+        self.ink.map_or(InkRole::Mark, |i| ink_classes[i].role)
+    }
+
+    /// Colors the light pixels of the bar in `image`, so the marks themselves stay visible
+    pub fn add_to_image(&self, rect: &BarRect, page_map: &PageMap, dark: &BooleanMatrix, fill_thresholds: &FillThresholds, image: &mut Image) {
+        let color = match self.state(fill_thresholds) {
+            BarState::Set => FILLED_COLOR,
+            BarState::Unset => EMPTY_COLOR,
+            BarState::Ambiguous => AMBIGUOUS_COLOR,
+            BarState::Erased => ERASED_COLOR,
+            BarState::Cancelled => CANCELLED_COLOR,
+        };
+
+        let pixels: Vec<_> = window(rect, page_map, image).collect();
+        for (x, y) in pixels.into_iter().filter(|&(x, y)| !dark.is_set(x, y)) {
+            image.set_color(x, y, color);
+        }
+    }
+}
+
+/// The mean of `colors`, if there are any
+fn mean_color(colors: &[Color]) -> Option<Color> {
+    if colors.is_empty() {
+        return None;
+    }
+
+    let mut color_sum = [0u64; 3];
+    for color in colors.iter() {
+        for (sum, channel) in color_sum.iter_mut().zip(color.rgb()) {
+            *sum += channel as u64;
+        }
+    }
+
+    let [r, g, b] = color_sum.map(|sum| (sum / colors.len() as u64) as u8);
+    Some(Color::from_rgb(r, g, b))
+}
+
+/// The median gray of `colors`, if there are any
+fn median_gray(colors: impl Iterator<Item=Color>) -> Option<f64> {
+    let mut grays: Vec<u8> = colors.map(|color| color.gray()).collect();
+    if grays.is_empty() {
+        return None;
+    }
+
+    let half = grays.len() / 2;
+    Some(*grays.select_nth_unstable(half).1 as f64)
+}
+
+/// The pixels of `image` whose centers land inside `rect` when mapped back onto the page
+fn window<'a>(rect: &'a BarRect, page_map: &'a PageMap, image: &Image) -> impl Iterator<Item=(usize, usize)> + 'a {
+    // the rectangle is small enough to stay convex, so its corners bound it
+    let corners = [(rect.left, rect.top), (rect.right, rect.top), (rect.right, rect.bottom), (rect.left, rect.bottom)]
+        .map(|corner| page_map.map(corner));
+
+    let clamp = |v: f64, size: usize| if v.is_finite() { v.max(0.0).min(size as f64) as usize } else { 0 };
+
+    let left = clamp(corners.iter().map(|c| c.0).fold(f64::INFINITY, f64::min).floor(), image.base);
+    let right = clamp(corners.iter().map(|c| c.0).fold(f64::NEG_INFINITY, f64::max).floor() + 1.0, image.base);
+    let top = clamp(corners.iter().map(|c| c.1).fold(f64::INFINITY, f64::min).floor(), image.height);
+    let bottom = clamp(corners.iter().map(|c| c.1).fold(f64::NEG_INFINITY, f64::max).floor() + 1.0, image.height);
+
+    (top..bottom)
+        .flat_map(move |y| (left..right).map(move |x| (x, y)))
+        .filter(move |&(x, y)| rect.contains(page_map.map_inverse((x as f64, y as f64))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::homography::Homography;
+    use crate::parse::scan_options::ScanOptions;
+
+    type Photo = (Image, BooleanMatrix, PageMap);
+    type Stroke = ((f64, f64), (f64, f64)); // from one end to the other
+
+    const SIZE: usize = 500;
+    const SLACK: f64 = 0.005;
+
+    const EMPTY: BarRect = BarRect { left: 0.2, top: 0.2, right: 0.23, bottom: 0.21 };
+    const BELOW: BarRect = BarRect { left: 0.2, top: 0.213, right: 0.23, bottom: 0.223 }; // as far below as in a digit
+
+    /// A page photographed square on, `SIZE` pixels a side, with pencil in `inked`
+    fn photo(inked: &[BarRect]) -> Photo {
+        photo_in_light(inked, 40, 240)
+    }
+
+    /// A page with paper of gray level `paper` and pencil of `pencil` in `inked`
+    fn photo_in_light(inked: &[BarRect], pencil: u8, paper: u8) -> Photo {
+        drawing(inked, &[], pencil, paper)
+    }
+
+    /// A page with pencil in `inked`, and along the lines of `strokes`
+    fn drawing(inked: &[BarRect], strokes: &[Stroke], pencil: u8, paper: u8) -> Photo {
+        const STROKE_WIDTH: f64 = 0.0024;
+
+        let on_stroke = |(x, y): (f64, f64)| strokes.iter().any(|&((x0, y0), (x1, y1))| {
+            let (dx, dy) = (x1 - x0, y1 - y0);
+            let t = (((x - x0) * dx + (y - y0) * dy) / (dx * dx + dy * dy)).clamp(0.0, 1.0);
+            (x - x0 - t * dx).hypot(y - y0 - t * dy) <= STROKE_WIDTH / 2.0
+        });
+
+        let scale = SIZE as f64;
+        let image = Image::from_fn(SIZE, SIZE, |x, y| {
+            let point = ((x as f64 + 0.5) / scale, (y as f64 + 0.5) / scale);
+            let level = if inked.iter().any(|rect| rect.contains(point)) || on_stroke(point) { pencil } else { paper };
+            Color::from_rgb(level, level, level)
+        });
+        let dark = BooleanMatrix::from_image(&image, &ScanOptions::default());
+        let page_map = PageMap::from_homography(Homography::scaling(scale, scale).unwrap());
+
+        (image, dark, page_map)
+    }
+
+    fn measure(rect: &BarRect, (image, dark, page_map): &Photo) -> BarFill {
+        BarFill::measure(rect, SLACK, &[EMPTY, BELOW], page_map, dark, image, &[])
+    }
+
+    #[test]
+    fn empty_bars_arent_shifted_onto_their_neighbors() {
+        // filled in past its edges, right up to the empty bar
+        let photo = photo(&[BELOW.grown(0.003)]);
+
+        assert!(measure(&EMPTY, &photo).fraction < FillThresholds::default().unset);
+        assert!(measure(&BELOW, &photo).fraction > 0.9);
+    }
+
+    #[test]
+    fn filled_bars_are_found_a_little_off() {
+        let photo = photo(&[EMPTY.shifted((0.004, -0.004))]);
+
+        assert!(measure(&EMPTY, &photo).fraction > 0.9);
+        assert!(measure(&BELOW, &photo).fraction < FillThresholds::default().unset);
+    }
+
+    fn state(rect: &BarRect, photo: &Photo) -> BarState {
+        measure(rect, photo).state(&FillThresholds::default())
+    }
+
+    #[test]
+    fn rubbed_out_pencil_is_erased_though_too_light_to_be_dark() {
+        let photo = photo_in_light(&[EMPTY], 170, 240);
+
+        assert_eq!(measure(&EMPTY, &photo).fraction, 0.0);
+        assert_eq!(state(&EMPTY, &photo), BarState::Erased);
+        assert_eq!(state(&BELOW, &photo), BarState::Unset);
+    }
+
+    #[test]
+    fn erasing_is_measured_against_the_paper() {
+        // the same pencil and residue, photographed in dim light
+        assert_eq!(state(&EMPTY, &photo_in_light(&[EMPTY], 27, 160)), BarState::Set);
+        assert_eq!(state(&EMPTY, &photo_in_light(&[EMPTY], 100, 160)), BarState::Set);
+        assert_eq!(state(&EMPTY, &photo_in_light(&[EMPTY], 113, 160)), BarState::Erased);
+    }
+
+    #[test]
+    fn residue_too_faint_to_see_is_empty() {
+        let photo = photo_in_light(&[EMPTY], 225, 240);
+
+        assert_eq!(state(&EMPTY, &photo), BarState::Unset);
+    }
+
+    const LONE: BarRect = BarRect { left: 0.5, top: 0.5, right: 0.53, bottom: 0.51 };
+
+    /// An X from corner to corner of `rect`, carried on `reach` past it
+    fn cross(rect: &BarRect, reach: f64) -> [Stroke; 2] {
+        let grown = rect.grown(reach);
+        [((grown.left, grown.top), (grown.right, grown.bottom)), ((grown.left, grown.bottom), (grown.right, grown.top))]
+    }
+
+    fn lone_state(photo: &Photo) -> BarState {
+        let (image, dark, page_map) = photo;
+        BarFill::measure(&LONE, SLACK, &[LONE], page_map, dark, image, &[]).state(&FillThresholds::default())
+    }
+
+    #[test]
+    fn crossed_out_filled_bars_are_cancelled() {
+        for reach in [0.012, 0.02] {
+            assert_eq!(lone_state(&drawing(&[LONE], &cross(&LONE, reach), 40, 240)), BarState::Cancelled);
+        }
+    }
+
+    #[test]
+    fn crossed_out_empty_bars_are_empty() {
+        for reach in [0.012, 0.02] {
+            assert_eq!(lone_state(&drawing(&[], &cross(&LONE, reach), 40, 240)), BarState::Unset);
+        }
+    }
+
+    #[test]
+    fn sloppy_fills_are_not_crossed_out() {
+        // about 1.7 mm past the bar all round
+        assert_eq!(lone_state(&drawing(&[LONE.grown(0.008)], &[], 40, 240)), BarState::Set);
+        // or a single stroke off one end
+        assert_eq!(lone_state(&drawing(&[LONE], &[((0.53, 0.505), (0.56, 0.505))], 40, 240)), BarState::Set);
+    }
+}
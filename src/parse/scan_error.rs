@@ -0,0 +1,79 @@
+use std::fmt;
+use std::io;
+
+/// Why a sheet couldn't be read.
+#[derive(Debug)]
+pub enum ScanError {
+    /// The image file couldn't be read
+    Io(io::Error),
+    /// The image file isn't in a format we can read
+    UnrecognizedFormat,
+    /// The image file isn't a valid PNG
+    Png(png::DecodingError),
+    /// The image file isn't a valid JPEG
+    Jpeg(jpeg_decoder::Error),
+    /// The image file isn't a valid TIFF, or uses a TIFF feature we can't read
+    Tiff(tiff::TiffError),
+    /// The image file isn't a valid PBM, PGM, or PPM
+    Netpbm(String),
+    /// The image uses a color type or bit depth we can't read
+    UnsupportedPixelFormat(String),
+    /// Fewer than three aligners were found, so the page can't be straightened out
+    MissingAligners {
+        /// How many aligners were found
+        found: usize,
+    },
+    /// The aligners were found in positions that can't come from a photo of a flat page, for example three in a line
+    DegenerateHomography,
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ScanError::Io(ref e) => write!(f, "could not read image: {}", e),
+            ScanError::UnrecognizedFormat => write!(f, "not a PNG, JPEG, TIFF, or Netpbm image"),
+            ScanError::Png(ref e) => write!(f, "could not decode image: {}", e),
+            ScanError::Jpeg(ref e) => write!(f, "could not decode image: {}", e),
+            ScanError::Tiff(ref e) => write!(f, "could not decode image: {}", e),
+            ScanError::Netpbm(ref reason) => write!(f, "could not decode image: {}", reason),
+            ScanError::UnsupportedPixelFormat(ref format) => write!(f, "unsupported pixel format: {}", format),
+            ScanError::MissingAligners { found } => write!(f, "found {} aligners, but at least 3 are needed", found),
+            ScanError::DegenerateHomography => write!(f, "the aligners found can't be the corners of a page"),
+        }
+    }
+}
+
+impl std::error::Error for ScanError {}
+
+impl From<io::Error> for ScanError {
+    fn from(error: io::Error) -> ScanError {
+        ScanError::Io(error)
+    }
+}
+
+impl From<png::DecodingError> for ScanError {
+    fn from(error: png::DecodingError) -> ScanError {
+        match error {
+            png::DecodingError::IoError(e) => ScanError::Io(e),
+            e => ScanError::Png(e),
+        }
+    }
+}
+
+impl From<jpeg_decoder::Error> for ScanError {
+    fn from(error: jpeg_decoder::Error) -> ScanError {
+        match error {
+            jpeg_decoder::Error::Io(e) => ScanError::Io(e),
+            e => ScanError::Jpeg(e),
+        }
+    }
+}
+
+impl From<tiff::TiffError> for ScanError {
+    fn from(error: tiff::TiffError) -> ScanError {
+        match error {
+            tiff::TiffError::IoError(e) => ScanError::Io(e),
+            e => ScanError::Tiff(e),
+        }
+    }
+}
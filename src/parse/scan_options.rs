@@ -0,0 +1,262 @@
+use crate::make::scan_sheet_elements::TEMPLATE_COLOR;
+use crate::make::scan_sheet_layout::BarState;
+use crate::parse::image::Color;
+
+const DARK_THRESHOLD: u8 = 110; // all pixels darker than this are target candidates
+const SET_FRACTION: f64 = 0.5;
+const UNSET_FRACTION: f64 = 0.2; // a stray pencil line or the edge of a neighbouring mark stays under this
+
+/// Settings for reading sheets, shared by every sheet in a scan.
+#[derive(Clone, Debug)]
+pub struct ScanOptions {
+    /// How pixels dark enough to be marks or aligners are told apart from the paper
+    pub threshold: Threshold,
+    /// Treats pixels the color of the printed template as paper, or keeps them if None
+    pub template_dropout: Option<TemplateDropout>,
+    /// The inks marks can be made in, tried in order. Colored inks count as dark even when they are bright, and each
+    /// filled in bar is tagged with the first ink its color matches. Bars in no listed ink are read as marks.
+    pub ink_classes: Vec<InkClass>,
+    /// How the filled in bars are found once the aligners are
+    pub read_mode: ReadMode,
+    /// How much of a bar has to be dark for it to be read as filled in, or as empty
+    pub fill_thresholds: FillThresholds,
+    /// How the straightened out page is sampled from the photo when reading in `ReadMode::Warp`
+    pub interpolation: Interpolation,
+}
+
+impl Default for ScanOptions {
+    fn default() -> ScanOptions {
+        ScanOptions {
+            threshold: Threshold::default(),
+            template_dropout: Some(TemplateDropout::default()),
+            ink_classes: Vec::new(),
+            read_mode: ReadMode::Warp,
+            fill_thresholds: FillThresholds::default(),
+            interpolation: Interpolation::Nearest,
+        }
+    }
+}
+
+/// How pixels dark enough to be marks or aligners are told apart from the paper. A pixel is only as dark as its
+/// brightest channel, so colored template ink stays light.
+#[derive(Clone, Copy, Debug)]
+pub enum Threshold {
+    /// Every pixel darker than this level is dark. Works for even lighting, like a flatbed scanner.
+    Fixed(u8),
+    /// One level for the whole image, chosen by Otsu's method to best split its pixels into dark and light
+    Otsu,
+    /// A pixel is dark if it is more than `offset` levels darker than the mean of the window around it
+    MeanOfWindow {
+        /// The side of the window, as a fraction of the image's shorter side
+        window: f64,
+        /// How many levels darker than the mean a pixel has to be
+        offset: f64,
+    },
+    /// Sauvola's method: a pixel is dark if it is darker than `mean * (1 + k * (deviation / 128 - 1))` of the window
+    /// around it. Windows with little contrast, like blank paper in a shadow, stay light.
+    Sauvola {
+        /// The side of the window, as a fraction of the image's shorter side
+        window: f64,
+        /// How far below the mean the level drops as contrast falls, usually between 0.2 and 0.5
+        k: f64,
+    },
+}
+
+impl Default for Threshold {
+    fn default() -> Threshold {
+        Threshold::Fixed(DARK_THRESHOLD)
+    }
+}
+
+/// How the filled in bars are found once the aligners have been.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ReadMode {
+    /// Straighten the page out into a square image about as many pixels across as the page is in the photo, and measure
+    /// how much of each bar of the layout is dark in it
+    Warp,
+    /// Measure how much of each bar of the layout is dark right where it lands in the photo. Faster than warping,
+    /// and it keeps the photo's full resolution.
+    Projection,
+}
+
+/// How much of a bar has to be dark, from 0 to 1, for it to be read as filled in or as empty. Bars in between, like a
+/// half filled bar or a smudge, are ambiguous, and the fields they are in are left for a person to check. `unset` should
+/// be no more than `set`.
+#[derive(Clone, Copy, Debug)]
+pub struct FillThresholds {
+    /// Bars at least this dark are filled in
+    pub set: f64,
+    /// Bars at most this dark are empty
+    pub unset: f64,
+}
+
+impl FillThresholds {
+    pub(crate) fn state(&self, score: f64) -> BarState {
+        if score >= self.set {
+            BarState::Set
+        } else if score <= self.unset {
+            BarState::Unset
+        } else {
+            BarState::Ambiguous
+        }
+    }
+}
+
+impl Default for FillThresholds {
+    fn default() -> FillThresholds {
+        FillThresholds { set: SET_FRACTION, unset: UNSET_FRACTION }
+    }
+}
+
+/// How colors are sampled from between the pixels of a photo when straightening out the page.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Interpolation {
+    /// The color of the pixel the point falls in, as the page has always been straightened out. Fastest, but thin bars
+    /// can alias and shift by up to a pixel.
+    Nearest,
+    /// A blend of the four nearest pixels
+    Bilinear,
+    /// A Catmull-Rom blend of the sixteen nearest pixels, which keeps edges sharper than bilinear
+    Bicubic,
+}
+
+/// Recognizes the ink the template is printed in by its hue, which survives dim or uneven lighting far better than
+/// its brightness does. Gray pencil has too little saturation to be mistaken for it, and blue pen too much. A cool white
+/// balance can tint pencil and aligners the template's hue, but being dark, they take on far more saturation than the
+/// template does, so they stay out too.
+#[derive(Clone, Copy, Debug)]
+pub struct TemplateDropout {
+    /// How many degrees a pixel's hue can be from the template's and still be template ink
+    pub hue_tolerance: f64,
+    /// The least saturation template ink has, from 0 to 1; the template itself has about 0.15
+    pub min_saturation: f64,
+    /// The most saturation template ink has, from 0 to 1
+    pub max_saturation: f64,
+}
+
+impl TemplateDropout {
+    pub(crate) fn is_template(&self, color: Color) -> bool {
+        let (template_hue, _) = TEMPLATE_COLOR.hue_saturation();
+        let (hue, saturation) = color.hue_saturation();
+
+        let hue_distance = (hue - template_hue).abs();
+        let hue_distance = hue_distance.min(360.0 - hue_distance); // hue wraps around
+
+        hue_distance <= self.hue_tolerance && (self.min_saturation..=self.max_saturation).contains(&saturation)
+    }
+}
+
+impl Default for TemplateDropout {
+    fn default() -> TemplateDropout {
+        TemplateDropout { hue_tolerance: 30.0, min_saturation: 0.09, max_saturation: 0.2 }
+    }
+}
+
+/// An ink that marks can be made in, recognized by its color.
+#[derive(Clone, Debug)]
+pub struct InkClass {
+    /// What to call the ink, like `red`
+    pub name: String,
+    /// The ink's hue in degrees, with red at 0, or None for neutral inks like graphite and black pen
+    pub hue: Option<f64>,
+    /// How many degrees a color's hue can be from the ink's and still match it
+    pub hue_tolerance: f64,
+    /// Colored inks have at least this much saturation, from 0 to 1, and neutral inks at most this much
+    pub saturation: f64,
+    /// What to do with marks in this ink
+    pub role: InkRole,
+}
+
+/// What to do with marks made in an ink.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum InkRole {
+    /// Read them as the answers on the sheet
+    Mark,
+    /// Leave them out, as if they weren't there
+    Ignore,
+    /// Read them separately, as corrections to the answers, like a teacher's red pen
+    Override,
+}
+
+impl InkClass {
+    /// Pencil, which is a dark gray with little color
+    pub fn graphite(role: InkRole) -> InkClass {
+        InkClass { name: String::from("graphite"), hue: None, hue_tolerance: 0.0, saturation: 0.25, role }
+    }
+
+    /// Blue pen
+    pub fn blue(role: InkRole) -> InkClass {
+        InkClass { name: String::from("blue"), hue: Some(225.0), hue_tolerance: 30.0, saturation: 0.4, role }
+    }
+
+    /// Red pen
+    pub fn red(role: InkRole) -> InkClass {
+        InkClass { name: String::from("red"), hue: Some(0.0), hue_tolerance: 25.0, saturation: 0.4, role }
+    }
+
+    pub(crate) fn matches(&self, color: Color) -> bool {
+        let (hue, saturation) = color.hue_saturation();
+
+        match self.hue {
+            Some(ink_hue) => {
+                let hue_distance = (hue - ink_hue).abs();
+                let hue_distance = hue_distance.min(360.0 - hue_distance);
+                hue_distance <= self.hue_tolerance && saturation >= self.saturation
+            },
+            None => saturation <= self.saturation,
+        }
+    }
+
+    pub(crate) fn is_colored(&self) -> bool {
+        self.hue.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::test_sheets::{PENCIL, BLUE_PEN, RED_PEN};
+
+    #[test]
+    fn template_ink_drops_out_in_any_light() {
+        let dropout = TemplateDropout::default();
+
+        for color in [TEMPLATE_COLOR, Color::from_rgb(0xA6, 0xB5, 0xC2), Color::from_rgb(0x7C, 0x88, 0x92), Color::from_rgb(0x50, 0x58, 0x60)] {
+            assert!(dropout.is_template(color), "{}", color);
+        }
+    }
+
+    #[test]
+    fn dark_bluish_pixels_are_not_template_ink() {
+        let dropout = TemplateDropout::default();
+
+        // pencil and aligners under a cool white balance have the template's hue, but more saturation
+        for color in [(20, 25, 32), (40, 45, 52), (60, 68, 80), (90, 100, 115)] {
+            let color = Color::from_rgb(color.0, color.1, color.2);
+            assert!(!dropout.is_template(color), "{}", color);
+        }
+    }
+
+    #[test]
+    fn pencil_and_pen_are_not_template_ink() {
+        let dropout = TemplateDropout::default();
+
+        for color in [Color::from_rgb(0x90, 0x90, 0x90), Color::from_rgb(0xD0, 0xD0, 0xD0), Color::from_rgb(0x20, 0x40, 0xC0)] {
+            assert!(!dropout.is_template(color), "{}", color);
+        }
+    }
+
+    #[test]
+    fn inks_are_told_apart_by_color() {
+        let [graphite, blue, red] = [InkClass::graphite, InkClass::blue, InkClass::red].map(|ink| ink(InkRole::Mark));
+
+        // pencil looks a little warm or cool depending on the light
+        for pencil in [PENCIL, Color::from_rgb(60, 55, 50), Color::from_rgb(50, 55, 65), Color::from_rgb(110, 110, 110)] {
+            assert!(graphite.matches(pencil), "{}", pencil);
+            assert!(!blue.matches(pencil), "{}", pencil);
+            assert!(!red.matches(pencil), "{}", pencil);
+        }
+        assert!(blue.matches(BLUE_PEN) && !graphite.matches(BLUE_PEN) && !red.matches(BLUE_PEN));
+        assert!(red.matches(RED_PEN) && !graphite.matches(RED_PEN) && !blue.matches(RED_PEN));
+    }
+}
@@ -32,6 +32,7 @@ pub struct Target {
 }
 
 impl Target {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(left: usize, right: usize, top: usize, bottom: usize, pixels_filled: usize, mean_x: usize, mean_y: usize, image_base: usize, image_height: usize) -> Option<Target> {
         let kind = TargetKind::classify(left, right, top, bottom, pixels_filled, image_base, image_height)?;
 
@@ -51,17 +52,9 @@ impl Target {
         })
     }
 
-    pub fn is_bar(&self) -> bool {
-        match self.kind {
-            TargetKind::HorizontalBar => true,
-            TargetKind::VerticalBar => true,
-            _ => false,
-        }
-    }
-
     pub fn center_position(&self) -> (f64, f64) {
         fn mean(a: f64, b: f64) -> f64 {
-            (a as f64 + b as f64)/2.0
+            (a + b)/2.0
         }
 
         let mean_x = mean(self.left, self.right);
@@ -113,15 +106,8 @@ impl TargetKind {
         let is_bar = (target_area_fraction-BAR_TARGET_AREA).abs() < BAR_TARGET_AREA_TOLERANCE
             && (squareness-BAR_SQUARENESS).abs() < BAR_SQUARENESS_TOLERANCE;
 
-        if is_bar {
-            println!("bar fullness: {:.03}\t\tsquareness: {}\t\timage_filled: {}", fullness, squareness, fraction_image_filled);
-        }
-
         match (is_bar, is_aligner) {
-            (false, false) => {
-                println!("mystery fullness: {:.03}\t\tsquareness: {}\t\ttarget_area: {}", fullness, squareness, target_area_fraction);
-                Some(TargetKind::Debug)
-            },
+            (false, false) => Some(TargetKind::Debug),
             (true, true) => None,
             (false, true) => Some(TargetKind::Aligner),
             (true, false) if is_tall => Some(TargetKind::VerticalBar),
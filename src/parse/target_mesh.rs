@@ -1,5 +1,6 @@
 use std::cmp::{max, min, Reverse};
 
+use crate::make::scan_sheet_elements::{ALIGNER_INNER_RADIUS, ALIGNER_OUTER_RADIUS};
 use crate::parse::boolean_matrix::BooleanMatrix;
 use crate::parse::image::{Image, Color};
 use crate::parse::target::Target;
@@ -7,9 +8,28 @@ use crate::parse::target::Target;
 use ordered_float::OrderedFloat;
 
 
+const ALIGNER_HOLE_FRACTION: f64 = ALIGNER_INNER_RADIUS / ALIGNER_OUTER_RADIUS; // how wide the hole of an aligner is, compared to the whole thing
+const DOT_FRACTION: f64 = 0.02; // a dot has about a twentieth as many pixels as its aligner's ring, and dirt much less than this isn't one
+const MARK_FRACTION: f64 = 0.01; // a timing mark has about a fortieth as many pixels as an aligner's ring, and dirt much less than this isn't one
+
+/// An aligner found in the image, which may be any of the page's fiducials
+#[derive(Clone, Copy, Debug)]
+pub struct AlignerCandidate {
+    pub center: (f64, f64),
+    pub dots: usize, // how many targets were found inside it
+}
+
 #[derive(Debug)]
 pub struct TargetMesh {
     pub targets: Vec<Target>, // coordinates stored are between 0 and 1 as height percentage
+    specks: Vec<Speck>, // what was too small or oddly shaped to be a target, which can still be a dot in an aligner
+}
+
+/// A blob of dark pixels that isn't a target
+#[derive(Clone, Copy, Debug)]
+struct Speck {
+    center: (f64, f64),
+    fraction_of_image_filled: f64,
 }
 
 
@@ -34,8 +54,8 @@ impl TargetMesh {
                 }
             }
 
-            for y in mean_y-target_center_square_size..=mean_y+target_center_square_size {
-                for x in mean_x-target_center_square_size..=mean_x+target_center_square_size {
+            for y in mean_y.saturating_sub(target_center_square_size)..=mean_y+target_center_square_size {
+                for x in mean_x.saturating_sub(target_center_square_size)..=mean_x+target_center_square_size {
                     if x < image.base && y < image.height {
                         image.set_color(x, y, Color::yellow());
                     }
@@ -44,64 +64,85 @@ impl TargetMesh {
         }
     }
 
-    pub fn get_bar_centers(&self) -> Vec<(f64, f64)> {
-        self.targets.iter()
-            .filter(|t| t.is_bar())
-            .map(|t| t.center_position())
-            .collect()
-    }
-
-    pub fn get_aligner_centers(&self) -> Vec<(f64, f64)> {
-        let mut aligners: Vec<Target> = self.targets.iter()
+    /// The `count` biggest aligners, biggest first
+    pub fn get_aligner_candidates(&self, count: usize) -> Vec<AlignerCandidate> {
+        let mut aligners: Vec<&Target> = self.targets.iter()
             .filter(|t| t.is_aligner())
-            .cloned()
             .collect();
 
         aligners.sort_by_key(|t| Reverse(OrderedFloat(t.fraction_of_image_filled)));
-        aligners.truncate(4);
-
-        assert_eq!(aligners.len(), 4, "Fewer than four aligners were found");
 
-        let mut centers: Vec<(f64, f64)>  = aligners.into_iter()
-            .map(|t| (t.mean_x as f64, t.mean_y as f64))
-            .collect();
+        aligners.into_iter()
+            .take(count)
+            .map(|aligner| AlignerCandidate { center: (aligner.mean_x, aligner.mean_y), dots: self.count_dots(aligner) })
+            .collect()
+    }
 
-        // we want to
-        let mut sorted_centers = Vec::with_capacity(4);
+    /// The centers of every target but the aligners. Timing marks in a photo where the page is small can be too small
+    /// to be targets of their own, so the specks at least about as big as one are included too.
+    pub fn get_other_centers(&self) -> Vec<(f64, f64)> {
+        let biggest_aligner = self.targets.iter()
+            .filter(|t| t.is_aligner())
+            .map(|t| t.fraction_of_image_filled)
+            .fold(None, |biggest: Option<f64>, filled| Some(biggest.map_or(filled, |biggest| biggest.max(filled))));
 
+        let targets = self.targets.iter()
+            .filter(|t| !t.is_aligner())
+            .map(|t| (t.mean_x, t.mean_y));
+        let specks = self.specks.iter()
+            .filter(|s| biggest_aligner.is_some_and(|aligner| s.fraction_of_image_filled >= MARK_FRACTION * aligner))
+            .map(|s| s.center);
 
-        sorted_centers.push(remove_max_by(&mut centers, |&(x0, y0), &(x1, y1)| x0+y0 < x1+y1)); // top left
-        sorted_centers.push(remove_max_by(&mut centers, |&(x0, y0), &(x1, y1)| x0-y0 > x1-y1)); // top right
-        sorted_centers.push(remove_max_by(&mut centers, |&(x0, y0), &(x1, y1)| x0+y0 > x1+y1)); // bottom right
-        sorted_centers.push(remove_max_by(&mut centers, |&(x0, y0), &(x1, y1)| x0-y0 < x1-y1)); // bottom left
+        targets.chain(specks).collect()
+    }
 
-        sorted_centers
+    /// How many dots are inside the hole of `aligner`. Dots in a photo where the page is small can be too small to be
+    /// targets of their own, so the specks are looked at too.
+    fn count_dots(&self, aligner: &Target) -> usize {
+        let (center_x, center_y) = aligner.center_position();
+
+        // the hole is a circle a little narrower than the ring, which a perspective turns into an ellipse
+        let radius_x = ALIGNER_HOLE_FRACTION * (aligner.right - aligner.left) / 2.0;
+        let radius_y = ALIGNER_HOLE_FRACTION * (aligner.bottom - aligner.top) / 2.0;
+        let min_filled = DOT_FRACTION * aligner.fraction_of_image_filled;
+
+        let targets = self.targets.iter()
+            .filter(|t| !t.is_aligner())
+            .map(|t| ((t.mean_x, t.mean_y), t.fraction_of_image_filled));
+        let specks = self.specks.iter().map(|s| (s.center, s.fraction_of_image_filled));
+
+        targets.chain(specks)
+            .filter(|&(_, filled)| filled >= min_filled)
+            .filter(|&((x, y), _)| ((x - center_x) / radius_x).powi(2) + ((y - center_y) / radius_y).powi(2) < 1.0)
+            .count()
     }
 
     pub fn from_matrix(target_candidates: &BooleanMatrix) -> TargetMesh {
         // lets iterate through all of the `dark` pixels
         let (base, height) = target_candidates.base_height();
 
-        let mut has_seen = BooleanMatrix::all_false(height, height);
+        let mut has_seen = BooleanMatrix::all_false(base, height);
 
         let mut targets = Vec::new(); // we add coordinates of the targets here
+        let mut specks = Vec::new();
 
         for y in 0..height {
             for x in 0..base {
                 if !target_candidates.is_set(x, y) || has_seen.is_set(x, y) { continue } // this pixel isn't a target, or we've already seen it
 
-                if let Some(target) = flood_fill(x, y, target_candidates, &mut has_seen) {
-                    targets.push(target);
+                match flood_fill(x, y, target_candidates, &mut has_seen) {
+                    Ok(target) => targets.push(target),
+                    Err(speck) => specks.push(speck),
                 }
             }
         }
 
-        TargetMesh { targets }
+        TargetMesh { targets, specks }
     }
 }
 
 
-fn flood_fill(x: usize, y: usize, target_candidates: &BooleanMatrix, has_seen: &mut BooleanMatrix) -> Option<Target> {
+fn flood_fill(x: usize, y: usize, target_candidates: &BooleanMatrix, has_seen: &mut BooleanMatrix) -> Result<Target, Speck> {
     // returns the topmost, rightmost, bottommost, leftmost point, and the total pixels filled
 
     let (image_base, image_height) = target_candidates.base_height();
@@ -198,6 +239,10 @@ fn flood_fill(x: usize, y: usize, target_candidates: &BooleanMatrix, has_seen: &
     let mean_y = y_sum / pixels_filled;
 
     Target::new(left, right, top, bottom, pixels_filled, mean_x, mean_y, image_base, image_height)
+        .ok_or(Speck {
+            center: (mean_x as f64 / image_base as f64, mean_y as f64 / image_height as f64),
+            fraction_of_image_filled: pixels_filled as f64 / (image_base*image_height) as f64,
+        })
 }
 
 fn neighbors(x: usize, y: usize, width: usize, height: usize) -> impl Iterator<Item=(usize, usize)> {
@@ -220,17 +265,53 @@ fn neighbors(x: usize, y: usize, width: usize, height: usize) -> impl Iterator<I
     ret.into_iter()
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-fn remove_max_by<T>(vec: &mut Vec<T>, greater_than: impl Fn(&T, &T) -> bool) -> T {
-    let mut max_index = 0;
-    let mut max = &vec[0];
-    for (i, k) in vec.iter().enumerate().skip(1) {
-        if greater_than(k, max) {
-            max = k;
-            max_index = i;
+    /// A 1000 pixel square image with an aligner 60 pixels across in the middle, the size it is when the page fills
+    /// only a fifth of the photo, and `dots` dots inside it
+    fn small_aligner(dots: usize, specks: &[(usize, usize)]) -> BooleanMatrix {
+        let (outer, inner) = (30.0, 30.0 * ALIGNER_HOLE_FRACTION);
+        let dot_radius = 5.0;
+        let orbit = if dots == 1 { 0.0 } else { 11.0 };
+
+        let dot_centers: Vec<(f64, f64)> = (0..dots)
+            .map(|i| i as f64 * std::f64::consts::TAU / dots as f64)
+            .map(|angle| (500.0 + orbit * angle.cos(), 500.0 + orbit * angle.sin()))
+            .collect();
+
+        let mut matrix = BooleanMatrix::all_false(1000, 1000);
+        for y in 400..600 {
+            for x in 400..600 {
+                let (fx, fy) = (x as f64, y as f64);
+                let distance = (fx - 500.0).hypot(fy - 500.0);
+                let in_dot = dot_centers.iter().any(|&(cx, cy)| (fx - cx).hypot(fy - cy) <= dot_radius);
+
+                if (inner..=outer).contains(&distance) || in_dot || specks.contains(&(x, y)) {
+                    matrix.set(x, y);
+                }
+            }
         }
+
+        matrix
     }
 
-    return vec.remove(max_index);
-}
+    #[test]
+    fn dots_too_small_to_be_targets_are_counted() {
+        for dots in 1..=4 {
+            let mesh = TargetMesh::from_matrix(&small_aligner(dots, &[]));
+            let aligners = mesh.get_aligner_candidates(4);
+
+            assert_eq!(aligners.len(), 1);
+            assert!(mesh.targets.iter().all(|t| t.is_aligner()), "the dots are too small to be targets");
+            assert_eq!(aligners[0].dots, dots);
+        }
+    }
 
+    #[test]
+    fn specks_of_dirt_are_not_dots() {
+        let mesh = TargetMesh::from_matrix(&small_aligner(2, &[(490, 510), (510, 490), (505, 515)]));
+        assert_eq!(mesh.get_aligner_candidates(4)[0].dots, 2);
+    }
+}
@@ -0,0 +1,65 @@
+//! Photos of filled in sheets, drawn straight from a layout, for tests that read a whole sheet.
+
+use crate::make::scan_sheet_elements::{ALIGNER_INNER_RADIUS, ALIGNER_OUTER_RADIUS, ALIGNER_DOT_RADIUS, ALIGNER_DOT_ORBIT};
+use crate::make::scan_sheet_layout::PageLayout;
+use crate::parse::homography::Homography;
+use crate::parse::image::{Image, Color};
+
+pub(crate) const PAPER: Color = Color::from_rgb(240, 240, 240);
+pub(crate) const TEMPLATE: Color = Color::from_rgb(207, 226, 243);
+pub(crate) const PENCIL: Color = Color::from_rgb(40, 40, 40);
+pub(crate) const BLUE_PEN: Color = Color::from_rgb(30, 50, 150);
+pub(crate) const RED_PEN: Color = Color::from_rgb(190, 30, 35);
+const PRINTED: Color = Color::from_rgb(10, 10, 10);
+
+/// The page, `fraction` of the shorter side of a `base` by `height` photo across, square on in the middle of it
+pub(crate) fn centered(base: usize, height: usize, fraction: f64) -> Homography {
+    let side = fraction * base.min(height) as f64;
+    let (left, top) = ((base as f64 - side) / 2.0, (height as f64 - side) / 2.0);
+
+    Homography::from_matrix([[side, 0.0, left], [0.0, side, top], [0.0, 0.0, 1.0]]).unwrap()
+}
+
+/// A `base` by `height` photo of a sheet made with `layout`, where the page lands by `page_to_photo`, with the bars of
+/// `marks`, by their index in layout order, filled in with their color
+pub(crate) fn photograph(layout: &PageLayout, marks: &[(usize, Color)], base: usize, height: usize, page_to_photo: &Homography) -> Image {
+    let geometry = layout.geometry();
+    let rects = layout.bar_rects();
+    let fiducials = geometry.fiducial_centers();
+
+    let mut dots = Vec::new();
+    for (i, &(x, y)) in fiducials.iter().enumerate() {
+        let count = geometry.aligner_dots(i);
+        let orbit = if count == 1 { 0.0 } else { ALIGNER_DOT_ORBIT };
+        for k in 0..count {
+            let angle = 2.0 * std::f64::consts::PI * k as f64 / count as f64;
+            dots.push((x + orbit * angle.sin(), y - orbit * angle.cos()));
+        }
+    }
+    let timing_marks: Vec<(f64, f64, f64)> = geometry.timing_tracks.iter()
+        .flat_map(|tracks| tracks.mark_centers().map(move |(x, y)| (x, y, tracks.mark_size / 2.0)))
+        .collect();
+
+    Image::from_fn(base, height, |x, y| {
+        let (px, py) = page_to_photo.map_inverse((x as f64 + 0.5, y as f64 + 0.5));
+        if !(0.0..=1.0).contains(&px) || !(0.0..=1.0).contains(&py) {
+            return Color::from_rgb(170, 160, 140); // the table the page is on
+        }
+
+        let in_ring = fiducials.iter()
+            .any(|&(cx, cy)| (ALIGNER_INNER_RADIUS..=ALIGNER_OUTER_RADIUS).contains(&(px - cx).hypot(py - cy)));
+        let in_dot = dots.iter().any(|&(cx, cy)| (px - cx).hypot(py - cy) <= ALIGNER_DOT_RADIUS);
+        let in_mark = timing_marks.iter().any(|&(cx, cy, half)| (px - cx).abs() <= half && (py - cy).abs() <= half);
+        if in_ring || in_dot || in_mark {
+            return PRINTED;
+        }
+
+        for (i, rect) in rects.iter().enumerate() {
+            if (rect.left..=rect.right).contains(&px) && (rect.top..=rect.bottom).contains(&py) {
+                return marks.iter().find(|&&(bar, _)| bar == i).map_or(TEMPLATE, |&(_, color)| color);
+            }
+        }
+
+        PAPER
+    })
+}